@@ -1,17 +1,30 @@
 use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::{fs, path};
 use toml;
 
+use generations;
 use filesystem;
+use scripts;
+use crypt;
 use common;
 use error;
 
 pub fn add_template(config_path: &str,
                     file_path: &str,
                     new_name: Option<&str>,
-                    templating_enabled: bool)
+                    templating_enabled: bool,
+                    encrypt: bool)
                     -> Result<(), error::DotfilerError> {
-    let mut config = common::load_config(config_path)?;
+    // Only the main config file is written back, included files are left untouched
+    let mut config = common::load_config_file(config_path)?;
+    let merged_config = common::load_config(config_path)?;
+    let variables = common::get_variables(&merged_config, None)?;
+
+    if encrypt && fs::metadata(file_path)?.is_dir() {
+        let msg = format!("Unable to add '{}', only files can be encrypted.", file_path);
+        return Err(error::DotfilerError::Message(msg));
+    }
 
     let templates_path = common::get_templates_path(config_path)?;
 
@@ -20,7 +33,29 @@ pub fn add_template(config_path: &str,
         None => &file_path[file_path.rfind('/').unwrap() + 1..],
     };
     let tar_path = templates_path.join(tar_path);
-    let tar_path = tar_path.to_string_lossy().to_string();
+    let mut tar_path = tar_path.to_string_lossy().to_string();
+
+    // Encrypted templates are recognized by their extension
+    if encrypt {
+        tar_path.push_str(crypt::EXTENSION);
+    }
+
+    // Dotfiles from included files can't be replaced, since those files are never written
+    let templates_dir = templates_path.to_string_lossy().to_string();
+    let in_main_config = match config.dotfiles {
+        Some(ref dotfiles) => {
+            template_exists_already(dotfiles, &templates_dir, &tar_path, file_path)?.is_some()
+        }
+        None => false,
+    };
+    if let Some(ref dotfiles) = merged_config.dotfiles {
+        if !in_main_config &&
+           template_exists_already(dotfiles, &templates_dir, &tar_path, file_path)?.is_some() {
+            let msg = format!("Unable to add '{}', the template is listed in an included config.",
+                              file_path);
+            return Err(error::DotfilerError::Message(msg));
+        }
+    }
 
     if let Some(ref mut dotfiles) = config.dotfiles {
         if let Some(duplicate_index) =
@@ -28,12 +63,7 @@ pub fn add_template(config_path: &str,
                                     &templates_path.to_string_lossy(),
                                     &tar_path,
                                     file_path)? {
-            println!("The template exists already. Do you want to update or overwrite it? [y/N]");
-
-            let mut buf = String::new();
-            io::stdin().read_line(&mut buf)?;
-
-            if buf.to_lowercase().trim() != "y" {
+            if !confirm("The template exists already. Do you want to update or overwrite it?")? {
                 println!("The file has not been added.");
                 return Ok(());
             } else {
@@ -64,18 +94,14 @@ pub fn add_template(config_path: &str,
         config.dotfiles = Some(Vec::new());
     }
 
-    // Back up old config to cache
-    if let Err(e) = fs::copy(&config_path, "./cache/config.toml") {
-        let msg = format!("Unable to save current config to backup cache:\n{}", e);
-        return Err(error::DotfilerError::Message(msg));
-    }
+    backup_config(config_path)?;
 
     // Create all required target directories before root
     let _ = path::Path::new(&tar_path)
         .parent()
         .map(|p| fs::create_dir_all(&p));
 
-    let mut root = match filesystem::create_tree_from_path(file_path, &tar_path) {
+    let mut root = match filesystem::create_template_tree(file_path, &tar_path) {
         Ok(root) => root,
         Err(e) => {
             let msg = format!("Can't create tree for file '{}':\n{}", file_path, e);
@@ -84,7 +110,7 @@ pub fn add_template(config_path: &str,
     };
 
     if templating_enabled {
-        if let Some(ref vars) = config.variables {
+        if let Some(ref vars) = variables {
             if let Err(e) = root.template(vars) {
                 let msg = format!("Unable to add the file '{}':\n{}", file_path, e);
                 return Err(error::DotfilerError::Message(msg));
@@ -92,43 +118,275 @@ pub fn add_template(config_path: &str,
         }
     }
 
-    if let Err(e) = root.save() {
+    // An overwritten template can be restored with a rollback like every other file
+    let mut generation = generations::Generation::new("/")?;
+    if let Err(e) = root.save(&generation.backup_path()) {
         let mut msg = format!("Unable to add the file '{}':\n{}", file_path, e);
 
         if let Err(e) = root.restore() {
             msg = format!("Critical Error! Unable to recover from failure.\n{}", e);
         }
 
+        generation.finish()?;
         return Err(error::DotfilerError::Message(msg));
     }
+    generation.add_changes(root.changes());
+    generation.finish()?;
 
     // Add new file to config
     let dotfile = common::Dotfile {
         template: tar_path.clone(),
         target: file_path.to_string(),
+        mode: None,
+        hosts: None,
+        os: None,
+        if_env: None,
+        if_exists: None,
+        owner: None,
+        group: None,
+        pre_deploy: None,
+        post_deploy: None,
+        permissions: None,
+        permission_patterns: None,
     };
 
     if let Some(ref mut dotfiles) = config.dotfiles {
         dotfiles.push(dotfile);
     }
 
-    // Save new config
-    let new_config = toml::to_string(&config)?;
+    save_config(config_path, &config)?;
+
+    println!("Successfully added '{}' to dotfiles.", file_path);
+    Ok(())
+}
+
+// Copy a script to the scripts directory and append it to the config
+pub fn add_script(config_path: &str,
+                  file_path: &str,
+                  new_name: Option<&str>,
+                  triggers: &[&str])
+                  -> Result<(), error::DotfilerError> {
+    // Only the main config file is written back, included files are left untouched
+    let mut config = common::load_config_file(config_path)?;
+    let merged_config = common::load_config(config_path)?;
+    let scripts_path = scripts::get_scripts_path(config_path)?.to_string_lossy().to_string();
+
+    let name = match new_name {
+        Some(name) => name,
+        None => &file_path[file_path.rfind('/').map_or(0, |i| i + 1)..],
+    };
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        let msg = format!("Invalid script name '{}', it has to be a file name without '/'.", name);
+        return Err(error::DotfilerError::Message(msg));
+    }
+    let tar_path = common::resolve_path(name, Some(&scripts_path))?;
+
+    // A script which is in the scripts directory already only has to be added to the config
+    let same_file = match (fs::canonicalize(file_path), fs::canonicalize(&tar_path)) {
+        (Ok(src), Ok(tar)) => src == tar,
+        _ => false,
+    };
+
+    let scripts = config.scripts.get_or_insert_with(Vec::new);
+    let duplicate_index = script_exists_already(scripts, &scripts_path, &tar_path)?;
+    let included = match merged_config.scripts {
+        Some(ref merged_scripts) if duplicate_index.is_none() => {
+            script_exists_already(merged_scripts, &scripts_path, &tar_path)?.is_some()
+        }
+        _ => false,
+    };
+    let exists = duplicate_index.is_some() || included || fs::metadata(&tar_path).is_ok();
+    if !same_file && exists &&
+       !confirm("The script exists already. Do you want to overwrite it?")? {
+        println!("The script has not been added.");
+        return Ok(());
+    }
+
+    backup_config(config_path)?;
+
+    // An overwritten script is backed up, so it can be restored like every other file
+    let mut generation = generations::Generation::new("/")?;
+    let root = if same_file {
+        None
+    } else {
+        match copy_script(file_path, &tar_path, &scripts_path, &generation.backup_path()) {
+            Ok(root) => Some(root),
+            Err(e) => {
+                generation.finish()?;
+                let msg = format!("Unable to add the script '{}':\n{}", file_path, e);
+                return Err(error::DotfilerError::Message(msg));
+            }
+        }
+    };
+
+    let triggers = if triggers.is_empty() {
+        None
+    } else {
+        Some(triggers.iter().map(|t| t.to_string()).collect())
+    };
+
+    // Scripts are executed in order, so an overwritten script keeps its position and options
+    match duplicate_index {
+        Some(i) => {
+            let script = overwrite_script(&scripts[i], name, triggers);
+            scripts[i] = script;
+        }
+        None if included => {
+            println!("The script is listed in an included config, which has not been changed.")
+        }
+        None => scripts.push(create_script(name, None, triggers)),
+    }
+    use_tables_if_required(scripts);
+
+    if let Err(e) = save_config(config_path, &config) {
+        if let Some(ref root) = root {
+            if let Err(e) = root.restore() {
+                println!("Critical Error! Unable to restore the old script.\n{}", e);
+            }
+        }
+        generation.finish()?;
+        return Err(e);
+    }
+
+    if let Some(ref root) = root {
+        generation.add_changes(root.changes());
+    }
+    generation.finish()?;
+
+    println!("Successfully added the script '{}'.", file_path);
+    Ok(())
+}
+
+// Copy a script to "tar_path", the old script is restored on failure
+fn copy_script(file_path: &str,
+               tar_path: &str,
+               scripts_path: &str,
+               backup_path: &str)
+               -> Result<Box<filesystem::File>, error::DotfilerError> {
+    fs::create_dir_all(scripts_path)?;
+    let mut root = filesystem::create_tree_from_path(file_path, tar_path)?;
+
+    // Scripts are executed directly, so everyone who can read them has to be able to execute them
+    let result = root.save(backup_path)
+        .and_then(|_| Ok(fs::metadata(tar_path)?))
+        .and_then(|meta| {
+            let mode = meta.permissions().mode();
+            let permissions = fs::Permissions::from_mode(mode | (mode & 0o444) >> 2);
+            Ok(fs::set_permissions(tar_path, permissions)?)
+        });
+    if let Err(e) = result {
+        if let Err(e) = root.restore() {
+            println!("Critical Error! Unable to restore the old script.\n{}", e);
+        }
+        return Err(e);
+    }
+
+    Ok(root)
+}
+
+// Scripts without options are stored as plain path
+fn create_script(path: &str,
+                 timeout: Option<u64>,
+                 triggers: Option<Vec<String>>)
+                 -> common::Script {
+    if timeout.is_none() && triggers.is_none() {
+        return common::Script::Path(path.to_string());
+    }
+
+    common::Script::Options {
+        path: path.to_string(),
+        timeout: timeout,
+        triggers: triggers,
+    }
+}
+
+// The timeout and triggers of the old script are kept unless new triggers are given
+fn overwrite_script(old: &common::Script,
+                    path: &str,
+                    triggers: Option<Vec<String>>)
+                    -> common::Script {
+    let triggers = triggers.or_else(|| old.triggers().map(|triggers| triggers.to_vec()));
+    create_script(path, old.timeout(), triggers)
+}
+
+// TOML arrays can't mix Strings and tables, so every script becomes a table if one is
+fn use_tables_if_required(scripts: &mut [common::Script]) {
+    if !scripts.iter().any(|script| script.triggers().is_some() || script.timeout().is_some()) {
+        return;
+    }
+
+    for script in scripts.iter_mut() {
+        let path = match *script {
+            common::Script::Path(ref path) => path.clone(),
+            _ => continue,
+        };
+
+        *script = common::Script::Options {
+            path: path,
+            timeout: None,
+            triggers: None,
+        };
+    }
+}
+
+// Ask a yes or no question, everything but "y" is treated as no
+fn confirm(question: &str) -> Result<bool, error::DotfilerError> {
+    println!("{} [y/N]", question);
+
+    let mut buf = String::new();
+    io::stdin().read_line(&mut buf)?;
+
+    Ok(buf.to_lowercase().trim() == "y")
+}
+
+// Back up old config to the state directory
+fn backup_config(config_path: &str) -> Result<(), error::DotfilerError> {
+    let state_path = common::get_state_path()?;
+    if let Err(e) = fs::create_dir_all(&state_path)
+           .and_then(|_| fs::copy(&config_path, get_config_backup_path(&state_path))) {
+        let msg = format!("Unable to save current config to backup cache:\n{}", e);
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    Ok(())
+}
+
+fn get_config_backup_path(state_path: &str) -> String {
+    [state_path, "/config.toml"].concat()
+}
+
+// Save new config, the backup is restored on failure
+fn save_config(config_path: &str, config: &common::Config) -> Result<(), error::DotfilerError> {
+    let new_config = toml::to_string(config)?;
     if let Err(e) = fs::File::create(common::resolve_path(config_path, None)?)
            .and_then(|mut f| f.write_all(new_config.as_bytes())) {
         let mut msg = format!("Unable to save new config:\n{}", e);
 
-        if let Err(e) = fs::copy("./cache/config.toml", &config_path) {
+        let restored = common::get_state_path()
+            .and_then(|state_path| fs::copy(get_config_backup_path(&state_path), &config_path));
+        if let Err(e) = restored {
             msg = format!("Unable to restore old config after failure:\n{}", e);
         }
 
         return Err(error::DotfilerError::Message(msg));
     }
 
-    println!("Successfully added '{}' to dotfiles.", file_path);
     Ok(())
 }
 
+fn script_exists_already(scripts: &[common::Script],
+                         scripts_path: &str,
+                         script_path: &str)
+                         -> Result<Option<usize>, error::DotfilerError> {
+    for (i, script) in scripts.iter().enumerate() {
+        if common::resolve_path(script.path(), Some(scripts_path))? == script_path {
+            return Ok(Some(i));
+        }
+    }
+
+    Ok(None)
+}
+
 fn template_exists_already(dotfiles: &[common::Dotfile],
                            templates_path: &str,
                            template_path: &str,
@@ -144,3 +402,140 @@ fn template_exists_already(dotfiles: &[common::Dotfile],
 
     Ok(None)
 }
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn add_encrypted_template() {
+    common::use_test_state();
+    crypt::use_test_key();
+    let _ = fs::create_dir_all("./add_encrypted");
+    fs::File::create("./add_encrypted/config.toml").unwrap();
+    fs::File::create("./add_encrypted/netrc")
+        .and_then(|mut f| f.write_all(b"password hunter2\n"))
+        .unwrap();
+
+    add_template("./add_encrypted/config.toml",
+                 "./add_encrypted/netrc",
+                 None,
+                 false,
+                 true)
+            .unwrap();
+    let stored = fs::read("./add_encrypted/templates/netrc.age");
+    let decrypted = crypt::read_file("./add_encrypted/templates/netrc.age");
+    let config = common::load_config_file("./add_encrypted/config.toml");
+
+    let _ = fs::remove_dir_all("./add_encrypted/");
+
+    assert!(stored.unwrap() != b"password hunter2\n");
+    assert_eq!(decrypted.unwrap(), b"password hunter2\n");
+    assert!(config.unwrap().dotfiles.unwrap()[0].template.ends_with("netrc.age"));
+}
+
+#[test]
+fn add_script_converting_paths_to_tables() {
+    common::use_test_state();
+    let _ = fs::create_dir_all("./add_script");
+    fs::File::create("./add_script/config.toml")
+        .and_then(|mut f| f.write_all(b"scripts = [\"a.sh\"]\n"))
+        .unwrap();
+    fs::File::create("./add_script/b.sh").unwrap();
+
+    add_script("./add_script/config.toml", "./add_script/b.sh", None, &["vim*"]).unwrap();
+    let executable = fs::metadata("./add_script/scripts/b.sh").map(|m| m.permissions().mode());
+    let config = common::load_config_file("./add_script/config.toml");
+
+    let _ = fs::remove_dir_all("./add_script/");
+
+    assert_eq!(executable.unwrap() & 0o100, 0o100);
+    assert_eq!(config.unwrap().scripts.unwrap(),
+               vec![common::Script::Options {
+                        path: String::from("a.sh"),
+                        timeout: None,
+                        triggers: None,
+                    },
+                    common::Script::Options {
+                        path: String::from("b.sh"),
+                        timeout: None,
+                        triggers: Some(vec![String::from("vim*")]),
+                    }]);
+}
+
+#[test]
+fn find_existing_scripts_by_resolved_path() {
+    let scripts = vec![common::Script::Path(String::from("a.sh")),
+                       common::Script::Path(String::from("sub/../b.sh"))];
+
+    assert_eq!(script_exists_already(&scripts, "/s", "/s/b.sh").unwrap(), Some(1));
+    assert_eq!(script_exists_already(&scripts, "/s", "/s/c.sh").unwrap(), None);
+}
+
+#[test]
+fn overwritten_scripts_keep_their_options() {
+    let old = common::Script::Options {
+        path: String::from("a.sh"),
+        timeout: Some(5),
+        triggers: Some(vec![String::from("vim*")]),
+    };
+
+    assert_eq!(overwrite_script(&old, "a.sh", None), old);
+    assert_eq!(overwrite_script(&old, "a.sh", Some(vec![String::from("zsh")])),
+               common::Script::Options {
+                   path: String::from("a.sh"),
+                   timeout: Some(5),
+                   triggers: Some(vec![String::from("zsh")]),
+               });
+    assert_eq!(overwrite_script(&common::Script::Path(String::from("a.sh")), "a.sh", None),
+               common::Script::Path(String::from("a.sh")));
+}
+
+#[test]
+fn templates_from_included_configs_are_duplicates() {
+    common::use_test_state();
+    let _ = fs::create_dir_all("./add_included");
+    fs::File::create("./add_included/config.toml")
+        .and_then(|mut f| f.write_all(b"include = [\"other.toml\"]\n"))
+        .unwrap();
+    fs::File::create("./add_included/other.toml")
+        .and_then(|mut f| f.write_all(b"[[dotfiles]]\ntemplate = \"x\"\ntarget = \"/tmp/x\"\n"))
+        .unwrap();
+    fs::File::create("./add_included/x").unwrap();
+
+    let result = add_template("./add_included/config.toml", "./add_included/x", None, false, false);
+    let config = common::load_config_file("./add_included/config.toml");
+
+    let _ = fs::remove_dir_all("./add_included/");
+
+    assert!(result.is_err());
+    assert!(config.unwrap().dotfiles.is_none());
+}
+
+#[test]
+fn add_script_from_the_scripts_directory() {
+    common::use_test_state();
+    let _ = fs::create_dir_all("./add_same/scripts");
+    fs::File::create("./add_same/config.toml").unwrap();
+    fs::File::create("./add_same/scripts/a.sh")
+        .and_then(|mut f| f.write_all(b"echo a\n"))
+        .unwrap();
+
+    let added = add_script("./add_same/config.toml", "./add_same/scripts/a.sh", None, &[]);
+    let outside =
+        add_script("./add_same/config.toml", "./add_same/scripts/a.sh", Some("../b"), &[]);
+    let content = fs::read("./add_same/scripts/a.sh");
+    let config = common::load_config_file("./add_same/config.toml");
+    let escaped = fs::metadata("./add_same/b").is_ok();
+
+    let _ = fs::remove_dir_all("./add_same/");
+
+    added.unwrap();
+    assert!(outside.is_err());
+    assert!(!escaped);
+    assert_eq!(content.unwrap(), b"echo a\n");
+    assert_eq!(config.unwrap().scripts.unwrap(),
+               vec![common::Script::Path(String::from("a.sh"))]);
+}
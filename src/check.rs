@@ -0,0 +1,320 @@
+use std::collections::BTreeSet;
+use std::os::unix::fs::PermissionsExt;
+use std::{fmt, fs, path};
+use handlebars::Template;
+use toml::value;
+
+use filesystem;
+use templates;
+use renderer;
+use helpers;
+use scripts;
+use system;
+use common;
+use error;
+
+#[derive(PartialEq)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+struct Finding {
+    severity: Severity,
+    message: String,
+}
+
+impl Finding {
+    fn error(message: String) -> Finding {
+        Finding {
+            severity: Severity::Error,
+            message: message,
+        }
+    }
+
+    fn warning(message: String) -> Finding {
+        Finding {
+            severity: Severity::Warning,
+            message: message,
+        }
+    }
+}
+
+// Validate the config and every template without writing anything
+// Returns true if there are no errors, warnings alone don't fail the check
+pub fn print(config_path: &str, profile: Option<&str>) -> Result<bool, error::DotfilerError> {
+    let config = common::load_config(config_path)?;
+    let templates_path = common::get_templates_path(config_path)?.to_string_lossy().to_string();
+    let scripts_path = scripts::get_scripts_path(config_path)?.to_string_lossy().to_string();
+
+    let mut findings = check_templates(&config, profile, &templates_path)?;
+    findings.append(&mut check_targets(&config));
+    findings.append(&mut check_scripts(&config, &scripts_path));
+
+    for finding in &findings {
+        println!("{}: {}", finding.severity, finding.message);
+    }
+
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    println!("Found {} errors and {} warnings.", errors, findings.len() - errors);
+
+    Ok(errors == 0)
+}
+
+// Every template has to exist and parse, variables have to be defined and used
+fn check_templates(config: &common::Config,
+                   profile: Option<&str>,
+                   templates_path: &str)
+                   -> Result<Vec<Finding>, error::DotfilerError> {
+    let mut findings = Vec::new();
+    let mut sources = Vec::new();
+
+    for dotfile in config.dotfiles.iter().flat_map(|dotfiles| dotfiles.iter()) {
+        match get_template_sources(dotfile, templates_path) {
+            Ok(mut template_sources) => sources.append(&mut template_sources),
+            Err(e) => findings.push(Finding::error(e.to_string())),
+        }
+    }
+
+    // Partials are checked even if no template includes them
+    sources.append(&mut helpers::read_partials(templates_path)?);
+
+    // Secrets are not resolved, their tables are enough to know that they are defined
+    let mut context = common::get_merged_variables(config, profile)?
+        .unwrap_or_else(value::Table::new);
+    if context.contains_key("sys") {
+        let msg = String::from("The variable 'sys' is reserved for the facts about the system.");
+        findings.push(Finding::error(msg));
+    }
+    context.insert("sys".into(), value::Value::Table(system::get_facts()));
+
+    let handlebars = helpers::create_handlebars();
+    let mut used = BTreeSet::new();
+    for (source, template) in sources {
+        if let Err(e) = Template::compile_with_name(&template, source.clone(), true) {
+            findings.push(Finding::error(e.to_string()));
+            continue;
+        }
+
+        for reference in renderer::get_references(&handlebars, &template) {
+            if !reference.optional && !renderer::is_defined(&reference.name, &context) {
+                findings.push(Finding::error(format!("{}:{}:{}: Undefined variable '{}'",
+                                                     source,
+                                                     reference.line,
+                                                     reference.column,
+                                                     reference.name)));
+            }
+
+            let name = reference.name.split(|c| c == '.' || c == '/').next().unwrap_or("");
+            used.insert(name.to_string());
+        }
+    }
+
+    let mut unused = |variables: &value::Table, location: &str| {
+        for name in variables.keys().filter(|name| !used.contains(*name)) {
+            let msg = format!("The variable '{}' in {} is never used.", name, location);
+            findings.push(Finding::warning(msg));
+        }
+    };
+
+    if let Some(ref variables) = config.variables {
+        unused(variables, "[variables]");
+    }
+    for (name, variables) in config.profiles.iter().flat_map(|profiles| profiles.iter()) {
+        unused(variables, &format!("[profiles.{}]", name));
+    }
+
+    Ok(findings)
+}
+
+// Every template String of a dotfile, linked templates are never rendered
+fn get_template_sources(dotfile: &common::Dotfile,
+                        templates_path: &str)
+                        -> Result<Vec<(String, String)>, error::DotfilerError> {
+    let template_path = common::resolve_path(&dotfile.template, Some(templates_path))
+        .map_err(|e| format!("Invalid template '{}':\n{}", dotfile.template, e))?;
+    if fs::symlink_metadata(&template_path).is_err() {
+        return Err(format!("The template '{}' does not exist.", template_path))?;
+    }
+
+    if common::is_linked(dotfile)? {
+        return Ok(Vec::new());
+    }
+
+    // Reading the sources doesn't depend on the target, invalid ones are in "check_targets"
+    let tar_path = templates::get_target_path(dotfile, "/").unwrap_or_else(|_| "/".into());
+    filesystem::create_tree_from_path(&template_path, &tar_path)
+        .and_then(|root| root.sources())
+        .map_err(|e| {
+                     let msg = format!("Unable to read the template '{}':\n{}", template_path, e);
+                     error::DotfilerError::Message(msg)
+                 })
+}
+
+// No two dotfiles which can be active at the same time may write to the same path
+fn check_targets(config: &common::Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let mut targets = Vec::new();
+    for dotfile in config.dotfiles.iter().flat_map(|dotfiles| dotfiles.iter()) {
+        match common::resolve_path(&dotfile.target, None) {
+            Ok(target) => targets.push((dotfile, target)),
+            Err(e) => {
+                let msg = format!("Invalid target '{}' of the template '{}':\n{}",
+                                  dotfile.target,
+                                  dotfile.template,
+                                  e);
+                findings.push(Finding::error(msg));
+            }
+        }
+    }
+
+    for (i, &(dotfile, ref target)) in targets.iter().enumerate() {
+        for &(other, ref other_target) in &targets[i + 1..] {
+            if !can_be_active_together(dotfile, other) {
+                continue;
+            }
+
+            let msg = if target == other_target {
+                format!("The templates '{}' and '{}' have the same target '{}'.",
+                        dotfile.template,
+                        other.template,
+                        target)
+            } else if path::Path::new(other_target).starts_with(target) {
+                format!("The target '{}' of '{}' is inside the target '{}' of '{}'.",
+                        other_target,
+                        other.template,
+                        target,
+                        dotfile.template)
+            } else if path::Path::new(target).starts_with(other_target) {
+                format!("The target '{}' of '{}' is inside the target '{}' of '{}'.",
+                        target,
+                        dotfile.template,
+                        other_target,
+                        other.template)
+            } else {
+                continue;
+            };
+            findings.push(Finding::error(msg));
+        }
+    }
+
+    findings
+}
+
+// Dotfiles for different hosts or operating systems never overlap
+fn can_be_active_together(dotfile: &common::Dotfile, other: &common::Dotfile) -> bool {
+    if let (Some(ref hosts), Some(ref other_hosts)) = (dotfile.hosts.as_ref(),
+                                                       other.hosts.as_ref()) {
+        if !hosts.iter().any(|host| other_hosts.contains(host)) {
+            return false;
+        }
+    }
+
+    match (dotfile.os.as_ref(), other.os.as_ref()) {
+        (Some(os), Some(other_os)) => os == other_os,
+        _ => true,
+    }
+}
+
+// Scripts are executed directly, so they have to be executable
+fn check_scripts(config: &common::Config, scripts_path: &str) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for script in config.scripts.iter().flat_map(|scripts| scripts.iter()) {
+        // A trigger without any matching dotfile means the script never runs
+        for trigger in script.triggers().unwrap_or(&[]) {
+            let matches_dotfile = config.dotfiles
+                .iter()
+                .flat_map(|dotfiles| dotfiles.iter())
+                .any(|dotfile| common::matches_pattern(trigger, &dotfile.template));
+            if !matches_dotfile {
+                let msg = format!("The trigger '{}' of the script '{}' matches no dotfile.",
+                                  trigger,
+                                  script.path());
+                findings.push(Finding::warning(msg));
+            }
+        }
+
+        let script_path = match common::resolve_path(script.path(), Some(scripts_path)) {
+            Ok(script_path) => script_path,
+            Err(e) => {
+                let msg = format!("Invalid script '{}':\n{}", script.path(), e);
+                findings.push(Finding::error(msg));
+                continue;
+            }
+        };
+
+        match fs::metadata(&script_path) {
+            Ok(ref meta) if !meta.is_file() => {
+                let msg = format!("The script '{}' is not a file.", script_path);
+                findings.push(Finding::error(msg));
+            }
+            Ok(ref meta) if meta.permissions().mode() & 0o111 == 0 => {
+                let msg = format!("The script '{}' is not executable.", script_path);
+                findings.push(Finding::error(msg));
+            }
+            Ok(_) => (),
+            Err(_) => {
+                let msg = format!("The script '{}' does not exist.", script_path);
+                findings.push(Finding::error(msg));
+            }
+        }
+    }
+
+    findings
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn overlapping_targets_are_errors() {
+    let config: common::Config =
+        ::toml::from_str("[[dotfiles]]\ntemplate = \"a\"\ntarget = \"/tmp/x\"\n\
+                          [[dotfiles]]\ntemplate = \"b\"\ntarget = \"/tmp/x/y\"\n\
+                          [[dotfiles]]\ntemplate = \"c\"\ntarget = \"/tmp/z\"\nhosts = [\"h1\"]\n\
+                          [[dotfiles]]\ntemplate = \"d\"\ntarget = \"/tmp/z\"\nhosts = [\"h2\"]")
+                .unwrap();
+    let findings = check_targets(&config);
+
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].message,
+               "The target '/tmp/x/y' of 'b' is inside the target '/tmp/x' of 'a'.");
+}
+
+#[test]
+fn invalid_templates_are_errors() {
+    use std::io::Write;
+    let _ = fs::create_dir_all("./check_templates");
+    fs::File::create("./check_templates/a")
+        .and_then(|mut f| f.write_all(b"plain text"))
+        .unwrap();
+    let config: common::Config =
+        ::toml::from_str("[[dotfiles]]\ntemplate = \"missing\"\ntarget = \"/tmp/x\"\n\
+                          [[dotfiles]]\ntemplate = \"a\"\ntarget = \"/tmp/y\"\n\
+                          mode = \"0600\"\n\
+                          [[dotfiles]]\ntemplate = \"a\"\ntarget = \"/tmp/z\"\n\
+                          mode = \"rw\"")
+                .unwrap();
+    let findings = check_templates(&config, None, "./check_templates");
+
+    let _ = fs::remove_dir_all("./check_templates/");
+
+    let findings = findings.unwrap();
+    assert_eq!(findings.len(), 2);
+    assert!(findings[0].message.ends_with("missing' does not exist."));
+    assert!(findings[1].message.starts_with("Unknown mode 'rw'"));
+}
@@ -1,53 +1,541 @@
 use std::io::{self, Read};
+use std::collections::BTreeMap;
 use std::{fs, env, path};
 use toml::{self, value};
-use std::process;
 
+use secrets;
+use system;
 use error;
 
+// Extension of config files with local overrides
+const LOCAL_EXTENSION: &'static str = "local.toml";
+
 #[derive(Serialize, Deserialize)]
 pub struct Config {
-    pub scripts: Option<Vec<String>>,
+    pub include: Option<Vec<String>>,
+    pub scripts: Option<Vec<Script>>,
     pub dotfiles: Option<Vec<Dotfile>>,
     pub variables: Option<value::Table>,
+    pub profiles: Option<BTreeMap<String, value::Table>>,
+}
+
+// Scripts are either a plain path or a table with additional options
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Script {
+    Path(String),
+    Options {
+        path: String,
+        // Seconds after which the script is killed
+        timeout: Option<u64>,
+        // Templates of dotfiles which have to change for the script to run, "*" and "?" match
+        triggers: Option<Vec<String>>,
+    },
+}
+
+impl Script {
+    pub fn path(&self) -> &str {
+        match *self {
+            Script::Path(ref path) |
+            Script::Options { ref path, .. } => path,
+        }
+    }
+
+    pub fn timeout(&self) -> Option<u64> {
+        match *self {
+            Script::Path(_) => None,
+            Script::Options { timeout, .. } => timeout,
+        }
+    }
+
+    // Without triggers, a script runs on every deployment
+    pub fn triggers(&self) -> Option<&[String]> {
+        match *self {
+            Script::Options { triggers: Some(ref triggers), .. } => Some(triggers),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Dotfile {
     pub template: String,
     pub target: String,
+    // Either "copy", "link" or octal permissions like "0600" for a copy, the default is "copy"
+    pub mode: Option<String>,
+    pub hosts: Option<Vec<String>>,
+    pub os: Option<String>,
+    pub if_env: Option<String>,
+    pub if_exists: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    // Commands which run before and after the dotfile is changed
+    pub pre_deploy: Option<String>,
+    pub post_deploy: Option<String>,
+    // Alias for octal permissions in "mode", patterns override them for matching files
+    pub permissions: Option<String>,
+    pub permission_patterns: Option<BTreeMap<String, String>>,
 }
 
+// Load the config with every included file and the local override file merged into it
 pub fn load_config(config_path: &str) -> Result<Config, error::DotfilerError> {
+    let config_path = resolve_path(config_path, None)?;
+    let mut config = load_config_file(&config_path)?;
+    let mut loaded = vec![config_path.clone()];
+    load_includes(&mut config, &config_path, &mut loaded)?;
+
+    // Untracked overrides for this machine, "config.toml" is overridden by "config.local.toml"
+    let local_path = path::Path::new(&config_path).with_extension(LOCAL_EXTENSION);
+    let local_path = local_path.to_string_lossy().to_string();
+    if !loaded.contains(&local_path) && fs::metadata(&local_path).is_ok() {
+        let mut local_config = load_config_file(&local_path)?;
+        loaded.push(local_path.clone());
+        load_includes(&mut local_config, &local_path, &mut loaded)?;
+        merge_configs(&mut config, local_config);
+    }
+
+    Ok(config)
+}
+
+// Load a single config file without following its includes
+pub fn load_config_file(config_path: &str) -> Result<Config, error::DotfilerError> {
     let config_path = resolve_path(config_path, None)?;
     let mut buffer = String::new();
-    fs::File::open(config_path)?
-        .read_to_string(&mut buffer)?;
-    Ok(toml::from_str(&buffer)?)
+    if let Err(e) = fs::File::open(&config_path).and_then(|mut f| f.read_to_string(&mut buffer)) {
+        let msg = format!("Unable to read the config '{}': {}", config_path, e);
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    toml::from_str(&buffer).map_err(|e| {
+        let msg = format!("Unable to parse the config '{}': Toml error: {}", config_path, e);
+        error::DotfilerError::Message(msg)
+    })
+}
+
+// Merge every file included by "config", in order, relative to the including file
+// Missing files are skipped with a warning, since untracked files don't exist on every machine
+fn load_includes(config: &mut Config,
+                 config_path: &str,
+                 loaded: &mut Vec<String>)
+                 -> Result<(), error::DotfilerError> {
+    let config_dir = path::Path::new(config_path).parent().unwrap().to_string_lossy().to_string();
+    for include in config.include.take().unwrap_or_else(Vec::new) {
+        let include_path = resolve_path(&include, Some(&config_dir))?;
+
+        // Every file is only merged once, this also prevents include loops
+        if loaded.contains(&include_path) {
+            continue;
+        }
+
+        if fs::metadata(&include_path).is_err() {
+            println!("Warning: Skipping the missing include '{}' of '{}'.",
+                     include,
+                     config_path);
+            continue;
+        }
+
+        let mut include_config = load_config_file(&include_path)?;
+        loaded.push(include_path.clone());
+        load_includes(&mut include_config, &include_path, loaded)?;
+        merge_configs(config, include_config);
+    }
+
+    Ok(())
+}
+
+// Variables and profiles of "other" override the ones in "base", dotfiles and scripts are appended
+fn merge_configs(base: &mut Config, other: Config) {
+    if let Some(mut scripts) = other.scripts {
+        base.scripts.get_or_insert_with(Vec::new).append(&mut scripts);
+    }
+
+    if let Some(mut dotfiles) = other.dotfiles {
+        base.dotfiles.get_or_insert_with(Vec::new).append(&mut dotfiles);
+    }
+
+    if let Some(ref variables) = other.variables {
+        merge_tables(base.variables.get_or_insert_with(value::Table::new), variables);
+    }
+
+    if let Some(profiles) = other.profiles {
+        let base_profiles = base.profiles.get_or_insert_with(BTreeMap::new);
+        for (name, variables) in profiles {
+            merge_tables(base_profiles.entry(name).or_insert_with(value::Table::new),
+                         &variables);
+        }
+    }
+}
+
+// Linked dotfiles are symlinked to the template instead of being copied
+pub fn is_linked(dotfile: &Dotfile) -> Result<bool, error::DotfilerError> {
+    match dotfile.mode.as_ref().map(String::as_str) {
+        None | Some("copy") => Ok(false),
+        Some("link") => Ok(true),
+        Some(mode) if parse_mode(mode).is_ok() => Ok(false),
+        Some(mode) => {
+            Err(format!("Unknown mode '{}' for the template '{}'.", mode, dotfile.template))?
+        }
+    }
+}
+
+// Octal permissions declared for the target, either in "mode" or in "permissions"
+pub fn get_mode(dotfile: &Dotfile) -> Result<Option<u32>, error::DotfilerError> {
+    let mode = match dotfile.mode.as_ref().map(String::as_str) {
+        None | Some("copy") | Some("link") => None,
+        Some(mode) => Some(mode),
+    };
+
+    match (mode, dotfile.permissions.as_ref()) {
+        (Some(_), Some(_)) => {
+            let msg = format!("The template '{}' declares both 'mode' and 'permissions'.",
+                              dotfile.template);
+            Err(error::DotfilerError::Message(msg))
+        }
+        (Some(mode), None) => Ok(Some(parse_mode(mode)?)),
+        (None, Some(permissions)) => Ok(Some(parse_mode(permissions)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+// Parse octal permissions like "0644" or "755"
+pub fn parse_mode(mode: &str) -> Result<u32, error::DotfilerError> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Invalid permissions '{}'.", mode))?,
+    }
+}
+
+// Resolve a user name or numeric uid
+pub fn get_uid(owner: &str) -> Result<u32, error::DotfilerError> {
+    get_id(owner, "/etc/passwd")
+}
+
+// Resolve a group name or numeric gid
+pub fn get_gid(group: &str) -> Result<u32, error::DotfilerError> {
+    get_id(group, "/etc/group")
+}
+
+// Look up the id in the third field of "/etc/passwd" or "/etc/group"
+fn get_id(name: &str, database: &str) -> Result<u32, error::DotfilerError> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    for fields in read_database(database)? {
+        if fields.len() > 2 && fields[0] == name {
+            if let Ok(id) = fields[2].parse() {
+                return Ok(id);
+            }
+        }
+    }
+
+    Err(format!("Unable to find '{}' in '{}'.", name, database))?
+}
+
+// Split every line of a colon separated database like "/etc/passwd"
+fn read_database(path: &str) -> Result<Vec<Vec<String>>, io::Error> {
+    let mut content = String::new();
+    fs::File::open(path)?.read_to_string(&mut content)?;
+
+    Ok(content.lines()
+           .map(|line| line.split(':').map(String::from).collect())
+           .collect())
+}
+
+// Match text against a glob pattern supporting "*" and "?"
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Position after the last "*" and the text position it has been matched up to
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last "*" consume one more character
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Check the conditions of a dotfile against the current machine
+// Returns the reason why the dotfile should be skipped
+pub fn get_skip_reason(dotfile: &Dotfile) -> Result<Option<String>, error::DotfilerError> {
+    if let Some(ref hosts) = dotfile.hosts {
+        let hostname = get_hostname()?;
+        if !hosts.contains(&hostname) {
+            return Ok(Some(format!("The host '{}' is not in {:?}.", hostname, hosts)));
+        }
+    }
+
+    if let Some(ref os) = dotfile.os {
+        if os != env::consts::OS {
+            return Ok(Some(format!("The OS is '{}' instead of '{}'.", env::consts::OS, os)));
+        }
+    }
+
+    if let Some(ref var) = dotfile.if_env {
+        match env::var_os(var) {
+            Some(ref val) if !val.is_empty() => (),
+            _ => return Ok(Some(format!("The environment variable '{}' is not set.", var))),
+        }
+    }
+
+    if let Some(ref path) = dotfile.if_exists {
+        if fs::symlink_metadata(resolve_path(path, None)?).is_err() {
+            return Ok(Some(format!("The path '{}' does not exist.", path)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Name of the profile which should be used
+// Without explicit profile, the profile matching the hostname is picked if it exists
+pub fn get_active_profile(config: &Config,
+                          profile: Option<&str>)
+                          -> Result<Option<String>, error::DotfilerError> {
+    let profile_exists = |name: &str| {
+        config.profiles.as_ref().map_or(false, |profiles| profiles.contains_key(name))
+    };
+
+    if let Some(profile) = profile {
+        if profile_exists(profile) {
+            return Ok(Some(profile.to_string()));
+        } else {
+            return Err(format!("The profile '{}' does not exist.", profile))?;
+        }
+    }
+
+    let hostname = get_hostname()?;
+    if profile_exists(&hostname) {
+        Ok(Some(hostname))
+    } else {
+        Ok(None)
+    }
+}
+
+// Variables with the active profile layered over the base "[variables]"
+// Secrets are left out, they are only available while rendering
+pub fn get_variables(config: &Config,
+                     profile: Option<&str>)
+                     -> Result<Option<value::Table>, error::DotfilerError> {
+    let mut variables = get_merged_variables(config, profile)?;
+    if let Some(ref mut variables) = variables {
+        secrets::remove(variables);
+    }
+
+    Ok(variables)
+}
+
+// Like "get_variables", but secrets are kept unresolved
+pub fn get_merged_variables(config: &Config,
+                            profile: Option<&str>)
+                            -> Result<Option<value::Table>, error::DotfilerError> {
+    let mut variables = config.variables.clone();
+
+    if let Some(profile) = get_active_profile(config, profile)? {
+        if let Some(profile_variables) = config.profiles.as_ref().and_then(|p| p.get(&profile)) {
+            merge_tables(variables.get_or_insert_with(value::Table::new), profile_variables);
+        }
+    }
+
+    if let Some(ref variables) = variables {
+        secrets::check_unwrapped(variables, "")?;
+    }
+
+    Ok(variables)
+}
+
+// Everything available while rendering, the variables and the read-only "sys" namespace
+// Every secret is resolved exactly once here, the returned secrets are used for redacting output
+pub fn get_context(config: &Config,
+                   profile: Option<&str>)
+                   -> Result<(value::Table, secrets::Secrets), error::DotfilerError> {
+    let mut context = get_unresolved_context(config, profile)?;
+    let secrets = secrets::resolve(&mut context)?;
+    Ok((context, secrets))
+}
+
+// Like "get_context", but only the secrets used by "references" are resolved
+pub fn get_referenced_context(config: &Config,
+                              profile: Option<&str>,
+                              references: &[String])
+                              -> Result<(value::Table, secrets::Secrets), error::DotfilerError> {
+    let mut context = get_unresolved_context(config, profile)?;
+    let secrets = secrets::resolve_referenced(&mut context, references)?;
+    Ok((context, secrets))
+}
+
+fn get_unresolved_context(config: &Config,
+                          profile: Option<&str>)
+                          -> Result<value::Table, error::DotfilerError> {
+    let mut context = get_merged_variables(config, profile)?.unwrap_or_else(value::Table::new);
+    if context.contains_key("sys") {
+        return Err(String::from("The variable 'sys' is reserved for the facts about the system."))?;
+    }
+
+    context.insert("sys".into(), value::Value::Table(system::get_facts()));
+    Ok(context)
+}
+
+// Recursively insert every value from "overrides" into "base"
+pub fn merge_tables(base: &mut value::Table, overrides: &value::Table) {
+    for (key, val) in overrides {
+        // Secrets are replaced as a whole, "command" and "file" must never be mixed
+        if secrets::is_secret(val) {
+            base.insert(key.clone(), val.clone());
+            continue;
+        }
+
+        if let value::Value::Table(ref override_table) = *val {
+            if let Some(&mut value::Value::Table(ref mut base_table)) = base.get_mut(key) {
+                merge_tables(base_table, override_table);
+                continue;
+            }
+        }
+
+        base.insert(key.clone(), val.clone());
+    }
+}
+
+pub fn get_hostname() -> Result<String, io::Error> {
+    let mut hostname = String::new();
+    fs::File::open("/proc/sys/kernel/hostname")?.read_to_string(&mut hostname)?;
+    Ok(hostname.trim().to_string())
 }
 
 // Rust can't deal with "~", "$HOME" or relative paths, this takes care of that
-// Also remove / at end of path
+// Also normalizes ".." and removes / at end of path without touching the filesystem
 pub fn resolve_path(path: &str, working_dir: Option<&str>) -> Result<String, io::Error> {
-    let mut command = format!("realpath -ms {}", path);
-    if working_dir.is_some() {
-        command = format!("cd {} && {}", working_dir.unwrap(), command);
+    let expanded = expand_variables(&expand_home(path)?)?;
+
+    let full_path = if expanded.starts_with('/') {
+        expanded
+    } else {
+        let base = match working_dir {
+            Some(working_dir) => resolve_path(working_dir, None)?,
+            None => env::current_dir()?.to_string_lossy().to_string(),
+        };
+        [&base, "/", &expanded].concat()
+    };
+
+    let mut components: Vec<&str> = Vec::new();
+    for component in full_path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
     }
 
-    let output = process::Command::new("sh")
-        .arg("-c")
-        .arg(&command)
-        .output()?;
+    Ok(["/", &components.join("/")].concat())
+}
 
-    if !output.stderr.is_empty() {
-        let msg = format!("Unable to resolve path using '{}':\n{}",
-                          command,
-                          String::from_utf8_lossy(&output.stderr).trim());
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+// Replace "~" and "~user" at the start of a path with the home directory
+fn expand_home(path: &str) -> Result<String, io::Error> {
+    if !path.starts_with('~') {
+        return Ok(path.to_string());
     }
 
-    let resolved_out = output.stdout;
-    Ok(String::from_utf8_lossy(&resolved_out).trim().to_string())
+    let user_end = path.find('/').unwrap_or(path.len());
+    let user = &path[1..user_end];
+
+    let home = if user.is_empty() {
+        env::var("HOME").ok()
+    } else {
+        get_user_home(user)?
+    };
+
+    let home = match home {
+        Some(home) => home,
+        None => {
+            let msg = format!("Unable to resolve path '{}': Unknown home directory.", path);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+    };
+
+    Ok([&home, &path[user_end..]].concat())
+}
+
+// Look up the home directory of a user in "/etc/passwd"
+fn get_user_home(user: &str) -> Result<Option<String>, io::Error> {
+    for mut fields in read_database("/etc/passwd")? {
+        if fields.len() > 5 && fields[0] == user {
+            return Ok(Some(fields.swap_remove(5)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Replace "$VAR" and "${VAR}" with the value of the environment variable
+fn expand_variables(path: &str) -> Result<String, io::Error> {
+    let mut expanded = String::new();
+    let mut chars = path.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => {
+                        let msg = format!("Unable to resolve path '{}': Missing '}}'.", path);
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                    }
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    name.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            // A single "$" is just a normal character
+            if name.is_empty() {
+                expanded.push('$');
+                continue;
+            }
+        }
+
+        match env::var(&name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => {
+                let msg = format!("Unable to resolve path '{}': Variable '{}' is not defined.",
+                                  path,
+                                  name);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+    }
+
+    Ok(expanded)
 }
 
 pub fn get_templates_path(config_path: &str) -> Result<path::PathBuf, io::Error> {
@@ -58,6 +546,16 @@ pub fn get_templates_path(config_path: &str) -> Result<path::PathBuf, io::Error>
            .join("templates"))
 }
 
+// Directory for data which has to persist between runs, like backups
+pub fn get_state_path() -> Result<String, io::Error> {
+    match env::var("XDG_STATE_HOME") {
+        Ok(ref state_home) if !state_home.is_empty() => {
+            resolve_path(&[state_home, "/dotfiler"].concat(), None)
+        }
+        _ => resolve_path("~/.local/state/dotfiler", None),
+    }
+}
+
 pub fn get_working_dir() -> Result<String, io::Error> {
     let mut app_dir = env::current_exe()?;
     app_dir.pop();
@@ -71,13 +569,143 @@ pub fn get_working_dir() -> Result<String, io::Error> {
 //     TESTS
 // -------------
 
-// Again, this requires the user to be undeadleech
+// Tests never touch the real state directory
+#[cfg(test)]
+pub fn use_test_state() {
+    static INIT: ::std::sync::Once = ::std::sync::Once::new();
+    INIT.call_once(|| {
+        let state_path = env::temp_dir().join(format!("dotfiler-state-{}", ::std::process::id()));
+        env::set_var("XDG_STATE_HOME", state_path);
+    });
+}
+
 #[test]
 fn resolve_home_path() {
+    let home = env::var("HOME").unwrap();
     assert_eq!(resolve_path("~/Programming", None).unwrap(),
-               "/home/undeadleech/Programming");
+               format!("{}/Programming", home));
     assert_eq!(resolve_path("$HOME/Programming", None).unwrap(),
-               "/home/undeadleech/Programming");
+               format!("{}/Programming", home));
+    assert_eq!(resolve_path("${HOME}/Programming", None).unwrap(),
+               format!("{}/Programming", home));
+}
+
+#[test]
+fn merge_tables_recursively() {
+    let mut base: value::Table = toml::from_str("a = 1\n[colors]\nfg = \"#000\"\nbg = \"#fff\"")
+        .unwrap();
+    let overrides: value::Table = toml::from_str("b = 2\n[colors]\nfg = \"#111\"").unwrap();
+    merge_tables(&mut base, &overrides);
+
+    let expected: value::Table = toml::from_str("a = 1\nb = 2\n[colors]\nfg = \"#111\"\nbg = \
+                                                 \"#fff\"")
+            .unwrap();
+    assert_eq!(base, expected);
+}
+
+#[test]
+fn match_glob_patterns() {
+    assert!(matches_pattern("*.sh", "run.sh"));
+    assert!(matches_pattern("polybar/*", "polybar/config"));
+    assert!(matches_pattern("a?c*d", "abcxxd"));
+    assert!(matches_pattern("*", ""));
+    assert!(!matches_pattern("*.sh", "run.shx"));
+    assert!(!matches_pattern("a?c", "ac"));
+}
+
+#[test]
+fn parse_octal_modes() {
+    assert_eq!(parse_mode("0600").unwrap(), 0o600);
+    assert_eq!(parse_mode("755").unwrap(), 0o755);
+    assert!(parse_mode("0800").is_err());
+    assert!(parse_mode("17777").is_err());
+}
+
+#[test]
+fn mode_is_copy_link_or_permissions() {
+    let config: Config = ::toml::from_str("[[dotfiles]]\ntemplate = \"a\"\ntarget = \"b\"\n\
+                                           mode = \"0600\"\n\
+                                           [[dotfiles]]\ntemplate = \"c\"\ntarget = \"d\"\n\
+                                           permissions = \"0644\"\n\
+                                           [[dotfiles]]\ntemplate = \"e\"\ntarget = \"f\"\n\
+                                           mode = \"link\"\n\
+                                           [[dotfiles]]\ntemplate = \"g\"\ntarget = \"h\"\n\
+                                           mode = \"0600\"\npermissions = \"0644\"\n\
+                                           [[dotfiles]]\ntemplate = \"i\"\ntarget = \"j\"\n\
+                                           mode = \"rw\"")
+            .unwrap();
+    let dotfiles = config.dotfiles.unwrap();
+
+    assert!(!is_linked(&dotfiles[0]).unwrap());
+    assert_eq!(get_mode(&dotfiles[0]).unwrap(), Some(0o600));
+    assert_eq!(get_mode(&dotfiles[1]).unwrap(), Some(0o644));
+    assert!(is_linked(&dotfiles[2]).unwrap());
+    assert_eq!(get_mode(&dotfiles[2]).unwrap(), None);
+    assert!(get_mode(&dotfiles[3]).is_err());
+    assert!(is_linked(&dotfiles[4]).is_err());
+}
+
+#[test]
+fn merge_configs_appending_dotfiles() {
+    let mut base: Config = toml::from_str("scripts = [\"a\"]\n[[dotfiles]]\ntemplate = \"x\"\n\
+                                           target = \"~/.x\"\n[variables]\nfg = \"#000\"\n\
+                                           bg = \"#fff\"")
+            .unwrap();
+    let other: Config = toml::from_str("[[dotfiles]]\ntemplate = \"y\"\ntarget = \"~/.y\"\n\
+                                        [variables]\nfg = \"#111\"")
+            .unwrap();
+    merge_configs(&mut base, other);
+
+    let templates: Vec<&str> = base.dotfiles
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|d| d.template.as_str())
+        .collect();
+    assert_eq!(templates, vec!["x", "y"]);
+    assert_eq!(base.scripts, Some(vec![Script::Path(String::from("a"))]));
+
+    let expected: value::Table = toml::from_str("fg = \"#111\"\nbg = \"#fff\"").unwrap();
+    assert_eq!(base.variables, Some(expected));
+}
+
+#[test]
+fn load_includes_in_order_skipping_missing_files() {
+    use std::io::Write;
+    let _ = fs::create_dir_all("./includes");
+    let write = |path: &str, content: &str| {
+        fs::File::create(path).and_then(|mut f| f.write_all(content.as_bytes()))
+    };
+    write("./includes/config.toml",
+          "include = [\"m.toml\", \"colors.toml\", \"machine.local.toml\"]")
+            .unwrap();
+    write("./includes/colors.toml", "[variables]\nfg = \"#000\"\nbg = \"#fff\"").unwrap();
+    write("./includes/m.toml", "[variables]\nfg = \"#111\"\nx = 1").unwrap();
+    write("./includes/broken.toml", "include = [\"colors.toml\"]\n[[dotfiles]]").unwrap();
+
+    let config = load_config("./includes/config.toml");
+    let broken = load_config("./includes/broken.toml");
+
+    let _ = fs::remove_dir_all("./includes/");
+
+    // Later includes override earlier ones
+    let expected: value::Table = toml::from_str("fg = \"#000\"\nbg = \"#fff\"\nx = 1").unwrap();
+    assert_eq!(config.unwrap().variables, Some(expected));
+    assert!(broken.is_err());
+}
+
+#[test]
+fn scripts_as_paths_or_tables() {
+    let paths: Config = toml::from_str("scripts = [\"a\"]").unwrap();
+    let tables: Config = toml::from_str("[[scripts]]\npath = \"b\"\ntimeout = 5\n\
+                                         triggers = [\"polybar/*\"]")
+            .unwrap();
+
+    assert_eq!(paths.scripts.unwrap()[0], Script::Path(String::from("a")));
+    assert_eq!(tables.scripts.as_ref().unwrap()[0].path(), "b");
+    assert_eq!(tables.scripts.as_ref().unwrap()[0].timeout(), Some(5));
+    assert_eq!(tables.scripts.as_ref().unwrap()[0].triggers(),
+               Some(&[String::from("polybar/*")][..]));
 }
 
 // Finally something that doesn't rely on anything
@@ -86,9 +714,32 @@ fn resolve_root_path() {
     assert_eq!(resolve_path("/root/test", None).unwrap(), "/root/test");
 }
 
+#[test]
+fn resolve_relative_path_with_working_dir() {
+    assert_eq!(resolve_path("../b c/./d/", Some("/root/a")).unwrap(),
+               "/root/b c/d");
+    assert_eq!(resolve_path("../../..", Some("/root")).unwrap(), "/");
+}
+
+#[test]
+fn resolve_path_without_executing_commands() {
+    assert_eq!(resolve_path("/tmp/a;ls 'b'", None).unwrap(), "/tmp/a;ls 'b'");
+}
+
+#[test]
+fn resolve_undefined_variable_fails() {
+    assert!(resolve_path("$DOTFILER_UNDEFINED_VARIABLE/test", None).is_err());
+}
+
 // Only checks last part of String to make it independent from compile path
 #[test]
 fn working_dir_ends_in_dotfiler_debug_deps_dir() {
     let working_dir = get_working_dir().unwrap();
     assert_eq!(working_dir.ends_with("dotfiler/target/debug/deps"), true);
 }
+
+#[test]
+fn sys_variable_is_reserved() {
+    let config: Config = ::toml::from_str("[variables]\nsys = \"x\"\n").unwrap();
+    assert!(get_context(&config, None).is_err());
+}
@@ -0,0 +1,143 @@
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::{env, fs, path};
+use age::{self, x25519};
+use age::secrecy::ExposeSecret;
+
+use common;
+use error;
+
+// Templates with this extension are stored encrypted with age
+pub const EXTENSION: &'static str = ".age";
+
+pub fn is_encrypted(path: &str) -> bool {
+    path.ends_with(EXTENSION)
+}
+
+// Read a file, decrypting it in memory if it is encrypted
+pub fn read_file(path: &str) -> Result<Vec<u8>, error::DotfilerError> {
+    let mut data = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut data)?;
+
+    if !is_encrypted(path) {
+        return Ok(data);
+    }
+
+    age::decrypt(&load_identity()?, &data).map_err(|e| {
+        let msg = format!("Unable to decrypt '{}': {}", path, e);
+        error::DotfilerError::Message(msg)
+    })
+}
+
+// Encrypt the data if the file at "path" has to be stored encrypted
+pub fn encrypt_for_path(path: &str, data: &[u8]) -> Result<Vec<u8>, error::DotfilerError> {
+    if !is_encrypted(path) {
+        return Ok(data.to_vec());
+    }
+
+    // Files are encrypted for the local key, so every machine with the key can decrypt them
+    let identity = match load_identity() {
+        Ok(identity) => identity,
+        Err(_) if fs::metadata(get_key_path()?).is_err() => generate_identity()?,
+        Err(e) => return Err(e),
+    };
+
+    age::encrypt(&identity.to_public(), data).map_err(|e| {
+        let msg = format!("Unable to encrypt '{}': {}", path, e);
+        error::DotfilerError::Message(msg)
+    })
+}
+
+// The key file can be changed with "$DOTFILER_KEY"
+fn get_key_path() -> Result<String, error::DotfilerError> {
+    match env::var("DOTFILER_KEY") {
+        Ok(ref key_path) if !key_path.is_empty() => Ok(common::resolve_path(key_path, None)?),
+        _ => Ok(common::resolve_path("~/.config/dotfiler/key.txt", None)?),
+    }
+}
+
+// Load the first age identity from the key file, lines starting with "#" are comments
+fn load_identity() -> Result<x25519::Identity, error::DotfilerError> {
+    let key_path = get_key_path()?;
+
+    let mut content = String::new();
+    if let Err(e) = fs::File::open(&key_path).and_then(|mut f| f.read_to_string(&mut content)) {
+        let msg = format!("Unable to read the key file '{}': {}", key_path, e);
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        return line.parse().map_err(|e| {
+            let msg = format!("Invalid key in the key file '{}': {}", key_path, e);
+            error::DotfilerError::Message(msg)
+        });
+    }
+
+    Err(format!("The key file '{}' does not contain a key.", key_path))?
+}
+
+// Create a new key file, only readable by the current user
+fn generate_identity() -> Result<x25519::Identity, error::DotfilerError> {
+    let key_path = get_key_path()?;
+    if let Some(parent_path) = path::Path::new(&key_path).parent() {
+        fs::create_dir_all(parent_path)?;
+    }
+
+    let identity = x25519::Identity::generate();
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&key_path)
+        .and_then(|mut f| {
+            write!(f,
+                   "# public key: {}\n{}\n",
+                   identity.to_public(),
+                   identity.to_string().expose_secret())
+        })?;
+
+    println!("Created the new key file '{}'.\nKeep a backup of it, encrypted templates can't be \
+              decrypted without it.",
+             key_path);
+    Ok(identity)
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+// Every test uses the same key, generated once per run in the temporary directory
+#[cfg(test)]
+pub fn use_test_key() {
+    static GENERATE: ::std::sync::Once = ::std::sync::Once::new();
+    GENERATE.call_once(|| {
+        let key_path = env::temp_dir().join(format!("dotfiler-key-{}.txt", ::std::process::id()));
+        env::set_var("DOTFILER_KEY", &key_path);
+        generate_identity().unwrap();
+    });
+}
+
+#[test]
+fn encrypt_and_decrypt_with_generated_key() {
+    use_test_key();
+    let encrypted = encrypt_for_path("./crypt_roundtrip.age", b"hunter2").unwrap();
+    fs::File::create("./crypt_roundtrip.age").and_then(|mut f| f.write_all(&encrypted)).unwrap();
+    let decrypted = read_file("./crypt_roundtrip.age");
+    let _ = fs::remove_file("./crypt_roundtrip.age");
+
+    assert!(encrypted != b"hunter2");
+    assert_eq!(decrypted.unwrap(), b"hunter2");
+}
+
+#[test]
+fn only_age_files_are_encrypted() {
+    assert!(is_encrypted("/templates/netrc.age"));
+    assert!(!is_encrypted("/templates/netrc"));
+    assert_eq!(encrypt_for_path("/templates/netrc", b"plain").unwrap(), b"plain");
+}
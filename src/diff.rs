@@ -0,0 +1,217 @@
+use std::cmp;
+
+use templates;
+use renderer;
+use common;
+use error;
+
+// Number of unchanged lines shown around every change
+const CONTEXT: usize = 3;
+
+pub fn print(config_path: &str,
+             profile: Option<&str>,
+             strict: bool)
+             -> Result<(), error::DotfilerError> {
+    let config = common::load_config(config_path)?;
+    let templates_path = common::get_templates_path(config_path)?.to_string_lossy().to_string();
+    let dotfiles = templates::get_active_dotfiles(&config, false);
+
+    // Secrets of templates which are not diffed are never resolved
+    // Templates which can't be read are reported while diffing
+    let references: Vec<String> = dotfiles.iter()
+        .filter_map(|dotfile| templates::get_references(dotfile, &templates_path).ok())
+        .flat_map(|references| references.into_iter())
+        .collect();
+    let (context, secrets) = common::get_referenced_context(&config, profile, &references)?;
+    let renderer = renderer::Renderer::new(&templates_path, strict)?;
+
+    let mut up_to_date = true;
+    let mut failed = 0;
+    for dotfile in dotfiles {
+        let root = match templates::create_tree(dotfile,
+                                                &renderer,
+                                                &context,
+                                                "/",
+                                                &templates_path) {
+            Ok(root) => root,
+            Err(e) => {
+                println!("{}", secrets.redact(&e.to_string()));
+                failed += 1;
+                continue;
+            }
+        };
+
+        match root.diff() {
+            Ok(ref diff) if diff.is_empty() => (),
+            Ok(diff) => {
+                up_to_date = false;
+                print!("{}", secrets.redact(&diff));
+            }
+            Err(e) => {
+                println!("Unable to diff the template '{}':\n{}",
+                         dotfile.template,
+                         secrets.redact(&e.to_string()));
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        println!("{} dotfile(s) could not be compared.", failed);
+    } else if up_to_date {
+        println!("Every file is up to date.");
+    }
+
+    Ok(())
+}
+
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+// Create a unified diff between two Strings
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let lines = diff_lines(&old_lines, &new_lines);
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_name, new_name);
+
+    // Collect the indices of all changed lines
+    let changes: Vec<usize> = lines.iter()
+        .enumerate()
+        .filter(|&(_, line)| match *line {
+                    Line::Same(_) => false,
+                    _ => true,
+                })
+        .map(|(i, _)| i)
+        .collect();
+
+    if changes.is_empty() {
+        diff.push_str("Only the line endings differ.\n");
+        return diff;
+    }
+
+    // Merge changes which are close to each other into a single hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for change in changes {
+        let start = change.saturating_sub(CONTEXT);
+        let end = cmp::min(change + CONTEXT + 1, lines.len());
+        match hunks.last_mut() {
+            Some(ref mut hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (start, end) in hunks {
+        // Count the lines in front of the hunk to get its position in both files
+        let (mut old_pos, mut new_pos) = (0, 0);
+        for line in &lines[..start] {
+            match *line {
+                Line::Same(_) => {
+                    old_pos += 1;
+                    new_pos += 1;
+                }
+                Line::Removed(_) => old_pos += 1,
+                Line::Added(_) => new_pos += 1,
+            }
+        }
+
+        let mut body = String::new();
+        let (mut old_count, mut new_count) = (0, 0);
+        for line in &lines[start..end] {
+            match *line {
+                Line::Same(text) => {
+                    old_count += 1;
+                    new_count += 1;
+                    body.push_str(&format!(" {}\n", text));
+                }
+                Line::Removed(text) => {
+                    old_count += 1;
+                    body.push_str(&format!("-{}\n", text));
+                }
+                Line::Added(text) => {
+                    new_count += 1;
+                    body.push_str(&format!("+{}\n", text));
+                }
+            }
+        }
+
+        diff.push_str(&format!("@@ -{} +{} @@\n{}",
+                               hunk_range(old_pos, old_count),
+                               hunk_range(new_pos, new_count),
+                               body));
+    }
+
+    diff
+}
+
+// Lines are numbered from 1, empty ranges point at the line in front of them
+fn hunk_range(pos: usize, count: usize) -> String {
+    if count == 0 {
+        format!("{},0", pos)
+    } else {
+        format!("{},{}", pos + 1, count)
+    }
+}
+
+// Line based diff using the longest common subsequence
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    lines
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn unified_diff_of_equal_strings_is_empty() {
+    assert_eq!(unified("a\nb\n", "a\nb\n", "old", "new"), "");
+}
+
+#[test]
+fn unified_diff_with_changed_line() {
+    let diff = unified("a\nb\nc\n", "a\nx\nc\n", "old", "new");
+    assert_eq!(diff, "--- old\n+++ new\n@@ -1,3 +1,3 @@\n a\n-b\n+x\n c\n");
+}
+
+#[test]
+fn unified_diff_with_new_file() {
+    let diff = unified("", "a\n", "/dev/null", "new");
+    assert_eq!(diff, "--- /dev/null\n+++ new\n@@ -0,0 +1,1 @@\n+a\n");
+}
@@ -11,6 +11,7 @@ pub enum DotfilerError {
     RusqliteError(rusqlite::Error),
     TomlSerializerError(toml::ser::Error),
     TemplateRenderError(Box<handlebars::TemplateRenderError>),
+    UndefinedVariables(Vec<String>),
 }
 
 impl fmt::Display for DotfilerError {
@@ -22,6 +23,7 @@ impl fmt::Display for DotfilerError {
             DotfilerError::RusqliteError(ref err) => write!(f, "Rusqlite error: {}", err),
             DotfilerError::TemplateRenderError(ref err) => write!(f, "Template error: {}", err),
             DotfilerError::TomlSerializerError(ref err) => write!(f, "Serializer error: {}", err),
+            DotfilerError::UndefinedVariables(ref errs) => write!(f, "{}", errs.join("\n")),
         }
     }
 }
@@ -35,6 +37,7 @@ impl error::Error for DotfilerError {
             DotfilerError::RusqliteError(ref err) => err.description(),
             DotfilerError::TemplateRenderError(ref err) => err.description(),
             DotfilerError::TomlSerializerError(ref err) => err.description(),
+            DotfilerError::UndefinedVariables(_) => "Undefined variables",
         }
     }
 
@@ -46,6 +49,7 @@ impl error::Error for DotfilerError {
             DotfilerError::RusqliteError(ref err) => Some(err),
             DotfilerError::TemplateRenderError(ref err) => Some(err),
             DotfilerError::TomlSerializerError(ref err) => Some(err),
+            DotfilerError::UndefinedVariables(_) => None,
         }
     }
 }
@@ -1,38 +1,82 @@
 use std::io::{self, Read, Write};
+use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::{fs, path};
 use std::os::unix;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use toml::value;
-use handlebars;
+use renderer::Renderer;
 use rusqlite;
+use tempfile;
 use walkdir;
 
 use common;
+use crypt;
 use error;
+use diff;
+use helpers;
+
+// Which side of a tree contains the templates
+#[derive(Clone, Copy)]
+enum Direction {
+    // Templates are the source and written to the targets
+    Deploy,
+    // Live files are the source and written to the templates
+    Pull,
+}
 
 pub fn create_tree_from_path(src_path: &str,
                              tar_path: &str)
                              -> Result<Box<File>, error::DotfilerError> {
+    create_tree(src_path, tar_path, Direction::Deploy)
+}
+
+// Create a tree which writes the live files at "src_path" to the templates at "tar_path"
+pub fn create_template_tree(src_path: &str,
+                            tar_path: &str)
+                            -> Result<Box<File>, error::DotfilerError> {
+    create_tree(src_path, tar_path, Direction::Pull)
+}
+
+fn create_tree(src_path: &str,
+               tar_path: &str,
+               direction: Direction)
+               -> Result<Box<File>, error::DotfilerError> {
     let src_path = common::resolve_path(src_path, None)?;
     let tar_path = common::resolve_path(tar_path, None)?;
 
     let filetype = fs::symlink_metadata(&src_path)?.file_type();
-    Ok(file_from_filetype(&filetype, &src_path, &tar_path)?)
+    Ok(file_from_filetype(&filetype, &src_path, &tar_path, direction)?)
+}
+
+// Create a symlink at "tar_path" pointing to "src_path" instead of copying it
+pub fn create_link(src_path: &str, tar_path: &str) -> Result<Box<File>, error::DotfilerError> {
+    let src_path = common::resolve_path(src_path, None)?;
+    let tar_path = common::resolve_path(tar_path, None)?;
+
+    Ok(Box::new(Symlink::to(&src_path, &tar_path)))
 }
 
 fn file_from_filetype(filetype: &fs::FileType,
                       src_path: &str,
-                      tar_path: &str)
+                      tar_path: &str,
+                      direction: Direction)
                       -> Result<Box<File>, error::DotfilerError> {
     if filetype.is_dir() {
-        return Ok(Box::new(Directory::new(src_path, tar_path)?));
+        return Ok(Box::new(Directory::new(src_path, tar_path, direction)?));
     } else if filetype.is_file() {
-        if is_sqlite(src_path)? {
-            return Ok(Box::new(SQLite::new(src_path, tar_path)?));
-        } else if is_binary(src_path) {
-            return Ok(Box::new(BinaryFile::new(src_path, tar_path)?));
-        } else {
-            return Ok(Box::new(TextFile::new(src_path, tar_path)?));
+        // Encrypted files are decrypted in memory first, so they are classified by their content
+        let data = crypt::read_file(src_path)?;
+        if is_sqlite(&data) {
+            return Ok(Box::new(SQLite::new(src_path, &data, tar_path)?));
         }
+
+        return match String::from_utf8(data) {
+                   Ok(text) => Ok(Box::new(TextFile::new(src_path, text, tar_path)?)),
+                   Err(e) => Ok(Box::new(BinaryFile::new(src_path, e.into_bytes(), tar_path)?)),
+               };
     } else if filetype.is_symlink() {
         return Ok(Box::new(Symlink::new(src_path, tar_path)?));
     }
@@ -40,74 +84,224 @@ fn file_from_filetype(filetype: &fs::FileType,
     Ok(Err(io::Error::new(io::ErrorKind::InvalidData, "FileType does not exist."))?)
 }
 
-fn is_binary(path: &str) -> bool {
-    let mut buf = String::new();
-    if let Err(e) = fs::File::open(path).and_then(|mut f| f.read_to_string(&mut buf)) {
-        if e.kind() == io::ErrorKind::InvalidData {
-            return true;
+fn is_sqlite(data: &[u8]) -> bool {
+    data.starts_with(b"SQLite")
+}
+
+// Encrypted templates are deployed without their extension and pulling the plain file updates
+// the encrypted template, which is only ever decided by looking at the templates
+fn get_child_target_path(src_path: &str, mut tar_path: String, direction: Direction) -> String {
+    match direction {
+        Direction::Deploy => {
+            if crypt::is_encrypted(src_path) {
+                let len = tar_path.len() - crypt::EXTENSION.len();
+                tar_path.truncate(len);
+            }
+        }
+        Direction::Pull => {
+            if !crypt::is_encrypted(&tar_path) &&
+               fs::metadata([&tar_path, crypt::EXTENSION].concat()).is_ok() {
+                tar_path.push_str(crypt::EXTENSION);
+            }
         }
     }
 
-    false
+    tar_path
 }
 
-fn is_sqlite(path: &str) -> Result<bool, io::Error> {
-    let mut f = fs::File::open(path)?;
-    if f.metadata()?.len() < 6 {
-        return Ok(false);
+// Permissions and ownership declared in the config
+pub struct PermissionRules {
+    // Target path of the dotfile, patterns are relative to it
+    pub root_path: String,
+    pub mode: Option<u32>,
+    pub patterns: Vec<(String, u32)>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl PermissionRules {
+    // Returns false if nothing has been declared for this path
+    fn apply(&self, permissions: &mut Permissions, path: &str, is_dir: bool) -> bool {
+        let relative_path = if path.len() > self.root_path.len() {
+            &path[self.root_path.len() + 1..]
+        } else {
+            ""
+        };
+
+        // The most specific, which is the longest, matching pattern wins
+        let mut mode = self.mode;
+        let mut pattern_len = 0;
+        for &(ref pattern, pattern_mode) in &self.patterns {
+            if pattern.len() >= pattern_len && common::matches_pattern(pattern, relative_path) {
+                mode = Some(pattern_mode);
+                pattern_len = pattern.len();
+            }
+        }
+
+        if let Some(mode) = mode {
+            // Directories need to be executable to access their content
+            permissions.mode = if is_dir { mode | (mode & 0o444) >> 2 } else { mode };
+        }
+        if self.uid.is_some() {
+            permissions.uid = self.uid;
+        }
+        if self.gid.is_some() {
+            permissions.gid = self.gid;
+        }
+
+        mode.is_some() || self.uid.is_some() || self.gid.is_some()
     }
+}
 
-    let mut buffer = [0; 6];
-    f.read_exact(&mut buffer)?;
+// Permissions and ownership a target has after saving
+struct Permissions {
+    mode: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
 
-    if String::from_utf8_lossy(&buffer) == "SQLite" {
-        Ok(true)
-    } else {
-        Ok(false)
+impl Permissions {
+    // By default the permissions of the source are used
+    fn from_path(path: &str) -> Result<Permissions, error::DotfilerError> {
+        Ok(Permissions {
+               mode: fs::metadata(path)?.mode() & 0o7777,
+               uid: None,
+               gid: None,
+           })
+    }
+
+    fn apply(&self, path: &str) -> Result<(), error::DotfilerError> {
+        fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        if self.uid.is_some() || self.gid.is_some() {
+            unix::fs::chown(path, self.uid, self.gid)?;
+        }
+
+        Ok(())
     }
+
+    // Describe how saving changes the permissions of an existing target
+    fn diff(&self, path: &str) -> String {
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(_) => return String::new(),
+        };
+
+        let mut diff = String::new();
+        if meta.mode() & 0o7777 != self.mode {
+            diff.push_str(&format!("Mode of '{}' changes from {:04o} to {:04o}\n",
+                                   path,
+                                   meta.mode() & 0o7777,
+                                   self.mode));
+        }
+        if let Some(uid) = self.uid {
+            if meta.uid() != uid {
+                diff.push_str(&format!("Owner of '{}' changes from {} to {}\n",
+                                       path,
+                                       meta.uid(),
+                                       uid));
+            }
+        }
+        if let Some(gid) = self.gid {
+            if meta.gid() != gid {
+                diff.push_str(&format!("Group of '{}' changes from {} to {}\n",
+                                       path,
+                                       meta.gid(),
+                                       gid));
+            }
+        }
+
+        diff
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.diff(path).is_empty()
+    }
+}
+
+// A file which has been created or overwritten while saving
+#[derive(Serialize, Deserialize)]
+pub struct Change {
+    pub target_path: String,
+    pub existed_already: bool,
+    // Previous mode of an existing directory whose mode has been changed
+    #[serde(default)]
+    pub mode: Option<u32>,
 }
 
 pub trait File {
-    fn save(&mut self) -> Result<(), error::DotfilerError>;
+    // Every overwritten file is backed up to "backup_path" + target path
+    // Returns the number of files which have actually been changed
+    fn save(&mut self, backup_path: &str) -> Result<usize, error::DotfilerError>;
     fn restore(&self) -> Result<(), error::DotfilerError>;
-    fn render(&mut self, &value::Table) -> Result<(), error::DotfilerError>;
+    // All files which have been changed by saving
+    fn changes(&self) -> Vec<Change>;
+    // Override the permissions taken from the source with the declared ones
+    fn set_permissions(&mut self, &PermissionRules);
+    fn render(&mut self, &Renderer, &value::Table) -> Result<(), error::DotfilerError>;
     fn template(&mut self, &value::Table) -> Result<(), error::DotfilerError>;
+    // Every template String of the file, together with the path it has been loaded from
+    fn sources(&self) -> Result<Vec<(String, String)>, error::DotfilerError>;
+    // Describe the differences between the target and the rendered file
+    // Returns an empty String if the target is up to date
+    fn diff(&self) -> Result<String, error::DotfilerError>;
 }
 
 struct Directory {
     files: Vec<Box<File>>,
     target_path: String,
+    permissions: Permissions,
+    declared_permissions: bool,
     existed_already: bool,
+    previous_mode: Option<u32>,
 }
 
 impl Directory {
-    fn new(file_path: &str, target_path: &str) -> Result<Directory, error::DotfilerError> {
+    fn new(file_path: &str,
+           target_path: &str,
+           direction: Direction)
+           -> Result<Directory, error::DotfilerError> {
         let mut files: Vec<Box<File>> = Vec::new();
-        for file in walkdir::WalkDir::new(&file_path).into_iter().filter_map(|e| e.ok()) {
+        // Only direct children, deeper levels are handled by the child directories
+        for file in walkdir::WalkDir::new(&file_path)
+                .max_depth(1)
+                .into_iter()
+                .filter_map(|e| e.ok()) {
             let file_str = file.path().to_string_lossy();
             if file_str != file_path {
                 let file_tar_path = [target_path, &file_str[file_path.len()..]].concat();
+                let file_tar_path = get_child_target_path(&file_str, file_tar_path, direction);
 
                 // Create specific File for every FileType possible
                 let filetype = file.file_type();
-                files.push(file_from_filetype(&filetype, &file_str, &file_tar_path)?);
+                files.push(file_from_filetype(&filetype, &file_str, &file_tar_path, direction)?);
             }
         }
 
         Ok(Directory {
                files: files,
                target_path: target_path.to_string(),
+               permissions: Permissions::from_path(file_path)?,
+               declared_permissions: false,
                existed_already: true,
+               previous_mode: None,
            })
     }
 }
 
 impl File for Directory {
     // First create the directory itself, then children
-    fn save(&mut self) -> Result<(), error::DotfilerError> {
+    fn save(&mut self, backup_path: &str) -> Result<usize, error::DotfilerError> {
+        let mut changed = 0;
         if let Ok(meta) = fs::metadata(&self.target_path) {
             if meta.is_dir() {
                 self.existed_already = true;
+
+                // Existing directories are only modified if permissions have been declared
+                if self.declared_permissions && !self.permissions.matches(&self.target_path) {
+                    self.previous_mode = Some(meta.mode() & 0o7777);
+                    self.permissions.apply(&self.target_path)?;
+                    changed += 1;
+                }
             } else {
                 // Path already exists but is no directory
                 let error_msg = format!("The path '{}' already exists but is not a directory.",
@@ -117,38 +311,89 @@ impl File for Directory {
         } else {
             self.existed_already = false;
             fs::create_dir(&self.target_path)?;
+            self.permissions.apply(&self.target_path)?;
+            changed += 1;
         }
 
         for file in &mut self.files {
-            file.save()?;
+            changed += file.save(backup_path)?;
         }
 
-        Ok(())
+        Ok(changed)
     }
 
     // Remove children first and then this directory
     fn restore(&self) -> Result<(), error::DotfilerError> {
-        let mut errors: Vec<Result<(), error::DotfilerError>> = Vec::new();
+        let mut errors: Vec<String> = Vec::new();
 
         for file in &self.files {
-            errors.push(file.restore());
+            if let Err(e) = file.restore() {
+                errors.push(e.to_string());
+            }
         }
 
         if !self.existed_already {
-            fs::remove_dir(&self.target_path)?;
+            if let Err(e) = fs::remove_dir(&self.target_path) {
+                errors.push(format!("Unable to remove directory '{}': {}", self.target_path, e));
+            }
+        } else if let Some(mode) = self.previous_mode {
+            let permissions = fs::Permissions::from_mode(mode);
+            if let Err(e) = fs::set_permissions(&self.target_path, permissions) {
+                errors.push(format!("Unable to restore the mode of '{}': {}", self.target_path, e));
+            }
         }
 
-        // Wait for unwrapping until everything is restored
+        // Wait for reporting until everything is restored
         if !errors.is_empty() {
-            errors.swap_remove(0)
+            Err(error::DotfilerError::Message(errors.join("\n")))
         } else {
             Ok(())
         }
     }
 
-    fn render(&mut self, variables: &value::Table) -> Result<(), error::DotfilerError> {
+    fn changes(&self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        if !self.existed_already || self.previous_mode.is_some() {
+            changes.push(Change {
+                             target_path: self.target_path.clone(),
+                             existed_already: self.existed_already,
+                             mode: self.previous_mode,
+                         });
+        }
+
+        for file in &self.files {
+            changes.append(&mut file.changes());
+        }
+
+        changes
+    }
+
+    fn set_permissions(&mut self, rules: &PermissionRules) {
+        self.declared_permissions = rules.apply(&mut self.permissions, &self.target_path, true);
+
         for file in &mut self.files {
-            file.render(variables)?;
+            file.set_permissions(rules);
+        }
+    }
+
+    // Undefined variables of every file are reported together
+    fn render(&mut self,
+              renderer: &Renderer,
+              variables: &value::Table)
+              -> Result<(), error::DotfilerError> {
+        let mut undefined = Vec::new();
+        for file in &mut self.files {
+            match file.render(renderer, variables) {
+                Err(error::DotfilerError::UndefinedVariables(mut errors)) => {
+                    undefined.append(&mut errors)
+                }
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            }
+        }
+
+        if !undefined.is_empty() {
+            return Err(error::DotfilerError::UndefinedVariables(undefined));
         }
 
         Ok(())
@@ -161,81 +406,144 @@ impl File for Directory {
 
         Ok(())
     }
+
+    fn sources(&self) -> Result<Vec<(String, String)>, error::DotfilerError> {
+        let mut sources = Vec::new();
+        for file in &self.files {
+            sources.append(&mut file.sources()?);
+        }
+
+        Ok(sources)
+    }
+
+    fn diff(&self) -> Result<String, error::DotfilerError> {
+        let mut diff = match fs::metadata(&self.target_path) {
+            Ok(ref meta) if !meta.is_dir() => {
+                format!("The path '{}' already exists but is not a directory.\n",
+                        self.target_path)
+            }
+            Ok(_) if self.declared_permissions => self.permissions.diff(&self.target_path),
+            Ok(_) => String::new(),
+            Err(_) => format!("New directory '{}'\n", self.target_path),
+        };
+
+        for file in &self.files {
+            diff.push_str(&file.diff()?);
+        }
+
+        Ok(diff)
+    }
 }
 
 struct TextFile {
     data: String,
+    src_path: String,
     target_path: String,
+    permissions: Permissions,
     backup_path: String,
     existed_already: bool,
+    saved: bool,
 }
 
 impl TextFile {
-    fn new(file_path: &str, target_path: &str) -> Result<TextFile, error::DotfilerError> {
-        // Create backup path and required directories
-        // parent_path can't fail since path is always at least "./cache" -> unwrap
-        let backup_path = &["./cache", target_path].concat();
-        let parent_path = path::Path::new(&backup_path).parent().unwrap();
-        fs::create_dir_all(&parent_path.to_string_lossy().to_string())?;
-
-        let mut existed_already = true;
-        if let Err(e) = fs::copy(target_path, backup_path) {
-            if e.kind() == io::ErrorKind::InvalidInput {
-                existed_already = false;
-            } else {
-                Err(e)?;
-            }
-        }
-
-        let mut data = String::new();
-        fs::File::open(file_path)?.read_to_string(&mut data)?;
-
+    fn new(file_path: &str,
+           data: String,
+           target_path: &str)
+           -> Result<TextFile, error::DotfilerError> {
         Ok(TextFile {
                data: data,
+               src_path: file_path.to_string(),
                target_path: target_path.to_string(),
-               backup_path: backup_path.to_string(),
-               existed_already: existed_already,
+               permissions: Permissions::from_path(file_path)?,
+               backup_path: String::new(),
+               existed_already: true,
+               saved: false,
            })
     }
 }
 
 impl File for TextFile {
-    fn save(&mut self) -> Result<(), error::DotfilerError> {
-        fs::File::create(&self.target_path).and_then(|mut f| f.write_all(self.data.as_bytes()))?;
+    fn save(&mut self, backup_path: &str) -> Result<usize, error::DotfilerError> {
+        if target_matches(&self.target_path, self.data.as_bytes()) &&
+           self.permissions.matches(&self.target_path) {
+            return Ok(0);
+        }
 
-        Ok(())
+        self.backup_path = [backup_path, &self.target_path].concat();
+        self.existed_already = backup_file(&self.target_path, &self.backup_path)?;
+        self.saved = true;
+        write_file(&self.target_path, self.data.as_bytes(), self.permissions.mode)?;
+        self.permissions.apply(&self.target_path)?;
+
+        Ok(1)
     }
 
     fn restore(&self) -> Result<(), error::DotfilerError> {
+        // Unchanged files have never been touched
+        if !self.saved {
+            return Ok(());
+        }
+
         if !self.existed_already {
             fs::remove_file(&self.target_path)?;
         } else {
             let _ = fs::remove_file(&self.target_path);
             fs::copy(&self.backup_path, &self.target_path)?;
+            copy_ownership(&self.backup_path, &self.target_path);
         }
 
         Ok(())
     }
 
-    fn render(&mut self, variables: &value::Table) -> Result<(), error::DotfilerError> {
-        let handlebars = handlebars::Handlebars::new();
-        self.data = handlebars.template_render(&self.data, variables)?;
+    fn changes(&self) -> Vec<Change> {
+        get_changes(self.saved, &self.target_path, self.existed_already)
+    }
+
+    fn set_permissions(&mut self, rules: &PermissionRules) {
+        rules.apply(&mut self.permissions, &self.target_path, false);
+    }
+
+    fn render(&mut self,
+              renderer: &Renderer,
+              variables: &value::Table)
+              -> Result<(), error::DotfilerError> {
+        self.data = renderer.render(&self.data, variables, &self.src_path)?;
 
         Ok(())
     }
 
     fn template(&mut self, variables: &value::Table) -> Result<(), error::DotfilerError> {
-        for (key, val) in variables {
-            let val_str = val.as_str()
-                .ok_or_else(|| {
-                                io::Error::new(io::ErrorKind::InvalidInput,
-                                               format!("Variable \"{}\" is not a String.", key))
-                            })?;
-            self.data = self.data.replace(val_str, &format!("{{{{ {} }}}}", key));
-        }
+        self.data = reverse_template(&self.data, &get_placeholders(variables)?);
 
         Ok(())
     }
+
+    fn sources(&self) -> Result<Vec<(String, String)>, error::DotfilerError> {
+        Ok(vec![(self.src_path.clone(), self.data.clone())])
+    }
+
+    fn diff(&self) -> Result<String, error::DotfilerError> {
+        if target_matches(&self.target_path, self.data.as_bytes()) {
+            return Ok(self.permissions.diff(&self.target_path));
+        }
+
+        if fs::metadata(&self.target_path).is_err() {
+            if self.data.is_empty() {
+                return Ok(format!("New empty file '{}'\n", self.target_path));
+            }
+
+            let new_name = format!("{} (rendered)", self.target_path);
+            return Ok(diff::unified("", &self.data, "/dev/null", &new_name));
+        }
+
+        // An encrypted target which can't be decrypted must not look like an empty file
+        let buffer = crypt::read_file(&self.target_path)?;
+        let old = String::from_utf8_lossy(&buffer);
+        let new_name = format!("{} (rendered)", self.target_path);
+        Ok([diff::unified(&old, &self.data, &self.target_path, &new_name),
+            self.permissions.diff(&self.target_path)]
+                   .concat())
+    }
 }
 
 struct Symlink {
@@ -243,55 +551,93 @@ struct Symlink {
     target_path: String,
     backup_path: String,
     existed_already: bool,
+    saved: bool,
+    // Only linked dotfiles replace whole directories, symlinks inside templates never do
+    replace_directories: bool,
 }
 
 impl Symlink {
     fn new(file_path: &str, target_path: &str) -> Result<Symlink, error::DotfilerError> {
-        // Copy old symlink to backup location
-        let mut existed_already = true;
-        let backup_path = &["./cache", target_path].concat();
-        if let Err(error::DotfilerError::IoError(e)) = copy_symlink(target_path, backup_path) {
-            if e.kind() == io::ErrorKind::NotFound {
-                existed_already = false;
-            } else {
-                Err(e)?;
-            }
-        }
-
         let symlink_tar_path = fs::read_link(&file_path)?;
-
         Ok(Symlink {
-               target: symlink_tar_path.to_string_lossy().to_string(),
-               target_path: target_path.to_string(),
-               backup_path: backup_path.to_string(),
-               existed_already: existed_already,
+               replace_directories: false,
+               ..Symlink::to(&symlink_tar_path.to_string_lossy(), target_path)
            })
     }
+
+    fn to(target: &str, target_path: &str) -> Symlink {
+        Symlink {
+            target: target.to_string(),
+            target_path: target_path.to_string(),
+            backup_path: String::new(),
+            existed_already: true,
+            saved: false,
+            replace_directories: true,
+        }
+    }
 }
 
 impl File for Symlink {
-    fn save(&mut self) -> Result<(), error::DotfilerError> {
+    fn save(&mut self, backup_path: &str) -> Result<usize, error::DotfilerError> {
+        if let Ok(current_target) = fs::read_link(&self.target_path) {
+            if current_target.to_string_lossy() == self.target {
+                return Ok(0);
+            }
+        }
+
+        let meta = fs::symlink_metadata(&self.target_path);
+        if !self.replace_directories && meta.as_ref().map(|m| m.is_dir()).unwrap_or(false) {
+            let error_msg = format!("The path '{}' already exists and is a directory.",
+                                    self.target_path);
+            Err(io::Error::new(io::ErrorKind::AlreadyExists, error_msg))?;
+        }
+
+        // Copy old file to backup location, this can be a symlink, file or directory
+        // Nothing is removed unless the backup is complete
+        self.backup_path = [backup_path, &self.target_path].concat();
+        self.existed_already = meta.is_ok();
         if self.existed_already {
-            fs::remove_file(&self.target_path)?;
+            copy_recursive(&self.target_path, &self.backup_path)?;
+        }
+
+        self.saved = true;
+        if self.existed_already {
+            remove_recursive(&self.target_path)?;
         }
 
         // Create symlink
-        Ok(unix::fs::symlink(&self.target, &self.target_path)?)
+        unix::fs::symlink(&self.target, &self.target_path)?;
+        Ok(1)
     }
 
     fn restore(&self) -> Result<(), error::DotfilerError> {
+        // Unchanged symlinks have never been touched
+        if !self.saved {
+            return Ok(());
+        }
+
         if !self.existed_already {
             fs::remove_file(&self.target_path)?;
         } else {
             let _ = fs::remove_file(&self.target_path);
-            copy_symlink(&self.backup_path, &self.target_path)?;
+            copy_recursive(&self.backup_path, &self.target_path)?;
         }
 
         Ok(())
     }
 
+    fn changes(&self) -> Vec<Change> {
+        get_changes(self.saved, &self.target_path, self.existed_already)
+    }
+
+    // Symlinks don't have their own permissions
+    fn set_permissions(&mut self, _rules: &PermissionRules) {}
+
     // Symlinks are not treated as files but just as links, so no rendering
-    fn render(&mut self, _variables: &value::Table) -> Result<(), error::DotfilerError> {
+    fn render(&mut self,
+              _renderer: &Renderer,
+              _variables: &value::Table)
+              -> Result<(), error::DotfilerError> {
         Ok(())
     }
 
@@ -299,135 +645,251 @@ impl File for Symlink {
     fn template(&mut self, _variables: &value::Table) -> Result<(), error::DotfilerError> {
         Ok(())
     }
+
+    fn sources(&self) -> Result<Vec<(String, String)>, error::DotfilerError> {
+        Ok(Vec::new())
+    }
+
+    fn diff(&self) -> Result<String, error::DotfilerError> {
+        match fs::read_link(&self.target_path) {
+            Ok(ref current) if current.to_string_lossy() == self.target => Ok(String::new()),
+            Ok(current) => {
+                Ok(format!("Symlink '{}' points to '{}' instead of '{}'\n",
+                           self.target_path,
+                           current.to_string_lossy(),
+                           self.target))
+            }
+            Err(_) if fs::symlink_metadata(&self.target_path).is_ok() => {
+                Ok(format!("The path '{}' already exists but is not a symlink to '{}'\n",
+                           self.target_path,
+                           self.target))
+            }
+            Err(_) => Ok(format!("New symlink '{}' -> '{}'\n", self.target_path, self.target)),
+        }
+    }
 }
 
 struct SQLite {
+    data: tempfile::NamedTempFile,
+    src_path: String,
     target_path: String,
+    permissions: Permissions,
     backup_path: String,
     existed_already: bool,
+    saved: bool,
 }
 
 impl SQLite {
-    fn new(file_path: &str, target_path: &str) -> Result<SQLite, error::DotfilerError> {
-        // Create backup path and required directories
-        // parent_path can't fail since path is always at least "./cache" -> unwrap
-        let backup_path = &["./cache", target_path].concat();
-        let parent_path = path::Path::new(&backup_path).parent().unwrap();
-        fs::create_dir_all(&parent_path.to_string_lossy().to_string())?;
-
-        let mut existed_already = true;
-        if let Err(e) = fs::copy(target_path, backup_path) {
-            if e.kind() == io::ErrorKind::InvalidInput {
-                existed_already = false;
-            } else {
-                Err(e)?;
-            }
-        }
-
-        // Overwrite the current file with the templated version
-        fs::copy(file_path, target_path)?;
+    fn new(file_path: &str,
+           content: &[u8],
+           target_path: &str)
+           -> Result<SQLite, error::DotfilerError> {
+        // Templating and rendering works on a temporary copy of the template,
+        // so the database can be compared with the target before saving
+        let mut data = tempfile::NamedTempFile::new()?;
+        data.write_all(content)?;
 
         Ok(SQLite {
+               data: data,
+               src_path: file_path.to_string(),
                target_path: target_path.to_string(),
-               backup_path: backup_path.to_string(),
-               existed_already: existed_already,
+               permissions: Permissions::from_path(file_path)?,
+               backup_path: String::new(),
+               existed_already: true,
+               saved: false,
            })
     }
+
+    fn data_path(&self) -> String {
+        self.data.path().to_string_lossy().to_string()
+    }
 }
 
 impl File for SQLite {
-    fn save(&mut self) -> Result<(), error::DotfilerError> {
-        // Templating and rendering works directly on the target file,
-        // this means saving isn't needed
-        Ok(())
+    fn save(&mut self, backup_path: &str) -> Result<usize, error::DotfilerError> {
+        if fs::metadata(&self.target_path).is_ok() {
+            if let (Ok(new), Ok(old)) = (dump_sqlite(&self.data_path()),
+                                         dump_sqlite(&self.target_path)) {
+                if new == old && self.permissions.matches(&self.target_path) {
+                    return Ok(0);
+                }
+            }
+        }
+
+        self.backup_path = [backup_path, &self.target_path].concat();
+        self.existed_already = backup_file(&self.target_path, &self.backup_path)?;
+        self.saved = true;
+        let mut content = Vec::new();
+        fs::File::open(self.data.path())?.read_to_end(&mut content)?;
+        write_file(&self.target_path, &content, self.permissions.mode)?;
+        self.permissions.apply(&self.target_path)?;
+
+        Ok(1)
     }
 
     fn restore(&self) -> Result<(), error::DotfilerError> {
+        // Unchanged databases have never been touched
+        if !self.saved {
+            return Ok(());
+        }
+
         if !self.existed_already {
             fs::remove_file(&self.target_path)?;
         } else {
             let _ = fs::remove_file(&self.target_path);
             fs::copy(&self.backup_path, &self.target_path)?;
+            copy_ownership(&self.backup_path, &self.target_path);
         }
 
         Ok(())
     }
 
-    fn render(&mut self, variables: &value::Table) -> Result<(), error::DotfilerError> {
-        fn modify(entry: &str, variables: &value::Table) -> Result<String, error::DotfilerError> {
-            let handlebars = handlebars::Handlebars::new();
-            Ok(handlebars.template_render(entry, variables)?)
-        };
+    fn changes(&self) -> Vec<Change> {
+        get_changes(self.saved, &self.target_path, self.existed_already)
+    }
+
+    fn set_permissions(&mut self, rules: &PermissionRules) {
+        rules.apply(&mut self.permissions, &self.target_path, false);
+    }
 
-        Ok(modify_sqlite_elements(&self.target_path, modify, variables)?)
+    fn render(&mut self,
+              renderer: &Renderer,
+              variables: &value::Table)
+              -> Result<(), error::DotfilerError> {
+        let modify = |entry: &str| renderer.render(entry, variables, &self.src_path);
+        modify_sqlite_elements(&self.data_path(), &modify)
     }
 
     fn template(&mut self, variables: &value::Table) -> Result<(), error::DotfilerError> {
-        fn modify(entry: &str, variables: &value::Table) -> Result<String, error::DotfilerError> {
-            let mut new_entry = entry.to_owned();
-            for (key, val) in variables {
-                if let Some(val) = val.as_str() {
-                    new_entry = new_entry.replace(&val, &format!("{{{{ {} }}}}", key));
-                }
+        let placeholders = get_placeholders(variables)?;
+        let modify = |entry: &str| Ok(reverse_template(entry, &placeholders));
+        modify_sqlite_elements(&self.data_path(), &modify)
+    }
+
+    // Every text element is a template on its own
+    fn sources(&self) -> Result<Vec<(String, String)>, error::DotfilerError> {
+        let elements = RefCell::new(Vec::new());
+        let collect = |entry: &str| {
+            elements.borrow_mut().push((self.src_path.clone(), entry.to_string()));
+            Ok(entry.to_string())
+        };
+        modify_sqlite_elements(&self.data_path(), &collect)?;
+
+        Ok(elements.into_inner())
+    }
+
+    fn diff(&self) -> Result<String, error::DotfilerError> {
+        if fs::metadata(&self.target_path).is_err() {
+            return Ok(format!("New SQLite database '{}'\n", self.target_path));
+        }
+
+        let new = dump_sqlite(&self.data_path())?;
+        let old = match dump_sqlite(&self.target_path) {
+            Ok(old) => old,
+            Err(_) => {
+                return Ok(format!("The path '{}' already exists but is not a SQLite database.\n",
+                                  self.target_path))
             }
-            Ok(new_entry)
         };
 
-        Ok(modify_sqlite_elements(&self.target_path, modify, variables)?)
+        let mut diff = String::new();
+        let empty = Vec::new();
+        for table in old.keys().chain(new.keys().filter(|t| !old.contains_key(*t))) {
+            let old_rows = old.get(table).unwrap_or(&empty);
+            let new_rows = new.get(table).unwrap_or(&empty);
+
+            let mut rows = String::new();
+            for row in old_rows.iter().filter(|r| !new_rows.contains(r)) {
+                rows.push_str(&format!("-{}\n", row));
+            }
+            for row in new_rows.iter().filter(|r| !old_rows.contains(r)) {
+                rows.push_str(&format!("+{}\n", row));
+            }
+
+            if !rows.is_empty() {
+                diff.push_str(&format!("Table '{}':\n{}", table, rows));
+            }
+        }
+
+        if !diff.is_empty() {
+            diff = format!("SQLite database '{}' differs:\n{}", self.target_path, diff);
+        }
+
+        diff.push_str(&self.permissions.diff(&self.target_path));
+        Ok(diff)
     }
 }
 
 struct BinaryFile {
-    src_path: String,
+    data: Vec<u8>,
     target_path: String,
+    permissions: Permissions,
     backup_path: String,
     existed_already: bool,
+    saved: bool,
 }
 
 impl BinaryFile {
-    fn new(file_path: &str, target_path: &str) -> Result<BinaryFile, error::DotfilerError> {
-        // Create backup path and required directories
-        // parent_path can't fail since path is always at least "./cache" -> unwrap
-        let backup_path = &["./cache", target_path].concat();
-        let parent_path = path::Path::new(&backup_path).parent().unwrap();
-        fs::create_dir_all(&parent_path.to_string_lossy().to_string())?;
-
-        let mut existed_already = true;
-        if let Err(e) = fs::copy(target_path, backup_path) {
-            if e.kind() == io::ErrorKind::InvalidInput {
-                existed_already = false;
-            } else {
-                Err(e)?;
-            }
-        }
-
+    fn new(file_path: &str,
+           data: Vec<u8>,
+           target_path: &str)
+           -> Result<BinaryFile, error::DotfilerError> {
         Ok(BinaryFile {
-               src_path: file_path.to_string(),
+               data: data,
                target_path: target_path.to_string(),
-               backup_path: backup_path.to_string(),
-               existed_already: existed_already,
+               permissions: Permissions::from_path(file_path)?,
+               backup_path: String::new(),
+               existed_already: true,
+               saved: false,
            })
     }
 }
 
 impl File for BinaryFile {
-    fn save(&mut self) -> Result<(), error::DotfilerError> {
-        fs::copy(&self.src_path, &self.target_path)?;
-        Ok(())
+    fn save(&mut self, backup_path: &str) -> Result<usize, error::DotfilerError> {
+        if target_matches(&self.target_path, &self.data) &&
+           self.permissions.matches(&self.target_path) {
+            return Ok(0);
+        }
+
+        self.backup_path = [backup_path, &self.target_path].concat();
+        self.existed_already = backup_file(&self.target_path, &self.backup_path)?;
+        self.saved = true;
+        write_file(&self.target_path, &self.data, self.permissions.mode)?;
+        self.permissions.apply(&self.target_path)?;
+
+        Ok(1)
     }
 
     fn restore(&self) -> Result<(), error::DotfilerError> {
+        // Unchanged files have never been touched
+        if !self.saved {
+            return Ok(());
+        }
+
         if !self.existed_already {
             fs::remove_file(&self.target_path)?;
         } else {
             let _ = fs::remove_file(&self.target_path);
             fs::copy(&self.backup_path, &self.target_path)?;
+            copy_ownership(&self.backup_path, &self.target_path);
         }
 
         Ok(())
     }
 
-    fn render(&mut self, _variables: &value::Table) -> Result<(), error::DotfilerError> {
+    fn changes(&self) -> Vec<Change> {
+        get_changes(self.saved, &self.target_path, self.existed_already)
+    }
+
+    fn set_permissions(&mut self, rules: &PermissionRules) {
+        rules.apply(&mut self.permissions, &self.target_path, false);
+    }
+
+    fn render(&mut self,
+              _renderer: &Renderer,
+              _variables: &value::Table)
+              -> Result<(), error::DotfilerError> {
         // Binary files can't be templated or rendered
         Ok(())
     }
@@ -436,6 +898,232 @@ impl File for BinaryFile {
         // Binary files can't be templated or rendered
         Ok(())
     }
+
+    fn sources(&self) -> Result<Vec<(String, String)>, error::DotfilerError> {
+        Ok(Vec::new())
+    }
+
+    // Binary files can't be diffed line by line, so only the size and hash are compared
+    fn diff(&self) -> Result<String, error::DotfilerError> {
+        let new = &self.data;
+        if fs::metadata(&self.target_path).is_err() {
+            return Ok(format!("New binary file '{}' ({} bytes)\n", self.target_path, new.len()));
+        }
+        let old = crypt::read_file(&self.target_path)?;
+
+        let permissions_diff = self.permissions.diff(&self.target_path);
+        if old == *new {
+            return Ok(permissions_diff);
+        }
+
+        Ok(format!("Binary file '{}' differs: size {} -> {} bytes, hash {:016x} -> {:016x}\n{}",
+                   self.target_path,
+                   old.len(),
+                   new.len(),
+                   hash_bytes(&old),
+                   hash_bytes(new),
+                   permissions_diff))
+    }
+}
+
+// A variable's rendered value and the placeholder which renders it
+struct Placeholder {
+    value: String,
+    placeholder: String,
+    // Numbers and booleans are only replaced as whole words, "1" should not match "10"
+    whole_word: bool,
+}
+
+// Placeholders for every variable, nested tables and arrays are flattened to paths like
+// "colors.fg" or "fonts.[0]"
+fn get_placeholders(variables: &value::Table) -> Result<Vec<Placeholder>, error::DotfilerError> {
+    let mut flat = Vec::new();
+    for (key, val) in variables {
+        flatten_variable(key.clone(), val, &mut flat);
+    }
+
+    // Render every path to match exactly what rendering the template would produce
+    let handlebars = helpers::create_handlebars();
+    let mut placeholders = Vec::new();
+    for (name, val) in flat {
+        let placeholder = format!("{{{{ {} }}}}", name);
+        let rendered = handlebars.template_render(&placeholder, variables)?;
+        if rendered.is_empty() {
+            continue;
+        }
+
+        placeholders.push(Placeholder {
+                              value: rendered,
+                              placeholder: placeholder,
+                              whole_word: !val.is_str(),
+                          });
+    }
+
+    // Longer values first, so a value contained in another one doesn't break it apart
+    placeholders.sort_by(|a, b| b.value.len().cmp(&a.value.len()));
+    Ok(placeholders)
+}
+
+fn flatten_variable<'a>(name: String,
+                        val: &'a value::Value,
+                        flat: &mut Vec<(String, &'a value::Value)>) {
+    match *val {
+        value::Value::Table(ref table) => {
+            for (key, val) in table {
+                flatten_variable(format!("{}.{}", name, key), val, flat);
+            }
+        }
+        value::Value::Array(ref array) => {
+            for (i, val) in array.iter().enumerate() {
+                flatten_variable(format!("{}.[{}]", name, i), val, flat);
+            }
+        }
+        _ => flat.push((name, val)),
+    }
+}
+
+// Replace every variable value in "data" with its placeholder
+fn reverse_template(data: &str, placeholders: &[Placeholder]) -> String {
+    let mut data = data.to_owned();
+    for placeholder in placeholders {
+        if !placeholder.whole_word {
+            data = data.replace(&placeholder.value, &placeholder.placeholder);
+            continue;
+        }
+
+        let mut templated = String::new();
+        let mut rest = data.as_str();
+        while let Some(start) = rest.find(&placeholder.value) {
+            let end = start + placeholder.value.len();
+            let is_word_char = |c: char| c.is_alphanumeric() || c == '.' || c == '_';
+            let before_ok = !rest[..start].chars().next_back().map_or(false, &is_word_char);
+            let after_ok = !rest[end..].chars().next().map_or(false, &is_word_char);
+
+            templated.push_str(&rest[..start]);
+            if before_ok && after_ok {
+                templated.push_str(&placeholder.placeholder);
+            } else {
+                templated.push_str(&placeholder.value);
+            }
+            rest = &rest[end..];
+        }
+        templated.push_str(rest);
+        data = templated;
+    }
+
+    data
+}
+
+// Check if the file at "path" already contains exactly "data"
+// Encrypted files are compared with their decrypted content
+fn target_matches(path: &str, data: &[u8]) -> bool {
+    match crypt::read_file(path) {
+        Ok(buffer) => buffer == data,
+        Err(_) => false,
+    }
+}
+
+// Write "data" to "path" and encrypt it if the path requires it
+// New files are created with the right mode, so secrets are never readable by others
+fn write_file(path: &str, data: &[u8], mode: u32) -> Result<(), error::DotfilerError> {
+    let data = crypt::encrypt_for_path(path, data)?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut f| f.write_all(&data))?;
+    Ok(())
+}
+
+fn get_changes(saved: bool, target_path: &str, existed_already: bool) -> Vec<Change> {
+    if saved {
+        vec![Change {
+                 target_path: target_path.to_string(),
+                 existed_already: existed_already,
+                 mode: None,
+             }]
+    } else {
+        Vec::new()
+    }
+}
+
+fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Copy the current target to the backup location
+// Returns false if there was no target which could be backed up
+fn backup_file(target_path: &str, backup_path: &str) -> Result<bool, error::DotfilerError> {
+    if fs::symlink_metadata(target_path).is_err() {
+        return Ok(false);
+    }
+
+    // Create required directories
+    // parent_path can't fail since path always contains the target path -> unwrap
+    let parent_path = path::Path::new(backup_path).parent().unwrap();
+    fs::create_dir_all(&parent_path.to_string_lossy().to_string())?;
+
+    // Copying keeps the mode, but the owner has to be copied too
+    fs::copy(target_path, backup_path)?;
+    copy_ownership(target_path, backup_path);
+    Ok(true)
+}
+
+// Copy the owner and group without following symlinks
+// Changing the owner usually requires root, so a failure only prints a warning
+fn copy_ownership(src: &str, tar: &str) {
+    let result = fs::symlink_metadata(src).and_then(|src_meta| {
+        let tar_meta = fs::symlink_metadata(tar)?;
+
+        // Only change ownership if required
+        if src_meta.uid() != tar_meta.uid() || src_meta.gid() != tar_meta.gid() {
+            unix::fs::lchown(tar, Some(src_meta.uid()), Some(src_meta.gid()))?;
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        println!("Warning: Unable to copy the owner of '{}' to '{}': {}", src, tar, e);
+    }
+}
+
+// Copy symlinks, files and directories without following symlinks
+pub fn copy_recursive(src: &str, tar: &str) -> Result<(), error::DotfilerError> {
+    let meta = fs::symlink_metadata(src)?;
+    if meta.file_type().is_symlink() {
+        copy_symlink(src, tar)?;
+    } else if meta.is_dir() {
+        fs::create_dir_all(tar)?;
+        fs::set_permissions(tar, meta.permissions())?;
+        for entry in fs::read_dir(src)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            copy_recursive(&[src, "/", &name].concat(), &[tar, "/", &name].concat())?;
+        }
+    } else {
+        if let Some(parent_path) = path::Path::new(tar).parent() {
+            fs::create_dir_all(parent_path)?;
+        }
+        fs::copy(src, tar)?;
+    }
+
+    copy_ownership(src, tar);
+    Ok(())
+}
+
+// Remove symlinks, files and directories without following symlinks
+pub fn remove_recursive(path: &str) -> Result<(), error::DotfilerError> {
+    if fs::symlink_metadata(path)?.file_type().is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
 }
 
 // Copy a symlink overwriting any existing file at "tar"
@@ -457,50 +1145,216 @@ fn copy_symlink(src: &str, tar: &str) -> Result<(), error::DotfilerError> {
 
 // Apply one single &str -> String method to every element in a SQLite DB
 fn modify_sqlite_elements(path: &str,
-                          func: fn(&str, &value::Table) -> Result<String, error::DotfilerError>,
-                          variables: &value::Table)
+                          func: &Fn(&str) -> Result<String, error::DotfilerError>)
                           -> Result<(), error::DotfilerError> {
     let db_conn = rusqlite::Connection::open(&path)?;
     let mut stmt = db_conn.prepare("SELECT tbl_name FROM sqlite_master WHERE type = 'table'")?;
-    let mut tables = stmt.query(&[])?;
+    let mut tables = stmt.query([])?;
 
-    while let Some(Ok(table)) = tables.next() {
-        let table: String = match table.get_checked(0) {
+    while let Ok(Some(table)) = tables.next() {
+        let table: String = match table.get(0) {
             Ok(table) => table,
             Err(_) => continue,
         };
 
         // Use format because apparently this doesn't work with rusqlite and '?'
         let mut stmt = db_conn.prepare(&format!("PRAGMA table_info({})", table))?;
-        let mut columns = stmt.query(&[])?;
+        let mut columns = stmt.query([])?;
 
-        while let Some(Ok(column)) = columns.next() {
-            let column: String = match column.get_checked(1) {
+        while let Ok(Some(column)) = columns.next() {
+            let column: String = match column.get(1) {
                 Ok(column) => column,
                 Err(_) => continue,
             };
 
             let mut stmt = db_conn.prepare(&format!("SELECT {} FROM {}", column, table))?;
-            let mut current_entries = stmt.query(&[])?;
+            let mut current_entries = stmt.query([])?;
 
-            while let Some(Ok(current_entry)) = current_entries.next() {
-                let mut current_entry: String = match current_entry.get_checked(0) {
+            while let Ok(Some(current_entry)) = current_entries.next() {
+                let mut current_entry: String = match current_entry.get(0) {
                     Ok(current_entry) => current_entry,
                     Err(_) => continue,
                 };
                 current_entry = current_entry.replace("'", "''");
 
-                let new_entry = func(&current_entry, variables)?;
+                let new_entry = func(&current_entry)?;
                 db_conn.execute(&format!("UPDATE {} SET {}='{}' WHERE {}='{}'",
                                          &table,
                                          &column,
                                          &new_entry,
                                          &column,
                                          &current_entry),
-                                &[])?;
+                                [])?;
             }
         }
     }
 
     Ok(())
 }
+
+// Read the content of every table in a SQLite DB
+fn dump_sqlite(path: &str) -> Result<BTreeMap<String, Vec<String>>, error::DotfilerError> {
+    let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY;
+    let db_conn = rusqlite::Connection::open_with_flags(&path, flags)?;
+    let mut stmt = db_conn.prepare("SELECT tbl_name FROM sqlite_master WHERE type = 'table'")?;
+    let mut tables = stmt.query([])?;
+
+    let mut dump = BTreeMap::new();
+    while let Ok(Some(table)) = tables.next() {
+        let table: String = match table.get(0) {
+            Ok(table) => table,
+            Err(_) => continue,
+        };
+
+        let mut stmt = db_conn.prepare(&format!("SELECT * FROM {}", table))?;
+        let column_count = stmt.column_count();
+        let mut rows = stmt.query([])?;
+
+        let mut table_rows = Vec::new();
+        while let Ok(Some(row)) = rows.next() {
+            let mut values = Vec::new();
+            for i in 0..column_count {
+                let value: rusqlite::types::Value = row.get(i)?;
+                values.push(format!("{:?}", value));
+            }
+            table_rows.push(values.join(", "));
+        }
+
+        dump.insert(table, table_rows);
+    }
+
+    Ok(dump)
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn reverse_template_non_string_variables() {
+    let variables: value::Table = ::toml::from_str("size = 10\nbold = true\n[colors]\nfg = \
+                                                    \"#ffffff\"\n")
+            .unwrap();
+    let placeholders = get_placeholders(&variables).unwrap();
+
+    let templated = reverse_template("fg=#ffffff\nsize=10\nwidth=100\nbold=true\n",
+                                     &placeholders);
+    assert_eq!(templated,
+               "fg={{ colors.fg }}\nsize={{ size }}\nwidth=100\nbold={{ bold }}\n");
+}
+
+#[test]
+fn restore_mode_of_existing_directories() {
+    let _ = fs::create_dir_all("./dir_mode/src");
+    let _ = fs::create_dir_all("./dir_mode/tar");
+    fs::set_permissions("./dir_mode/tar", fs::Permissions::from_mode(0o700)).unwrap();
+
+    let mut root = create_tree_from_path("./dir_mode/src", "./dir_mode/tar").unwrap();
+    root.set_permissions(&PermissionRules {
+                              root_path: common::resolve_path("./dir_mode/tar", None).unwrap(),
+                              mode: Some(0o750),
+                              patterns: Vec::new(),
+                              uid: None,
+                              gid: None,
+                          });
+    let changed = root.save("./dir_mode/backup").unwrap();
+    let changed_mode = fs::metadata("./dir_mode/tar").unwrap().mode() & 0o7777;
+    let changes = root.changes();
+    root.restore().unwrap();
+    let restored_mode = fs::metadata("./dir_mode/tar").unwrap().mode() & 0o7777;
+
+    let _ = fs::remove_dir_all("./dir_mode/");
+
+    assert_eq!(changed, 1);
+    assert_eq!(changed_mode, 0o750);
+    assert_eq!(changes[0].mode, Some(0o700));
+    assert_eq!(restored_mode, 0o700);
+}
+
+#[test]
+fn encrypted_targets_are_decided_by_templates() {
+    let _ = fs::create_dir_all("./age_mapping");
+    fs::File::create("./age_mapping/b.age").unwrap();
+
+    let deployed = get_child_target_path("./t/a.age", String::from("./h/a.age"), Direction::Deploy);
+    let plain = get_child_target_path("./t/b", String::from("./age_mapping/b"), Direction::Deploy);
+    let pulled = get_child_target_path("./h/b", String::from("./age_mapping/b"), Direction::Pull);
+
+    let _ = fs::remove_dir_all("./age_mapping/");
+
+    assert_eq!(deployed, "./h/a");
+    assert_eq!(plain, "./age_mapping/b");
+    assert_eq!(pulled, "./age_mapping/b.age");
+}
+
+#[test]
+fn deploy_encrypted_templates_decrypted() {
+    crypt::use_test_key();
+    let _ = fs::create_dir_all("./age_deploy/templates");
+    let _ = fs::create_dir_all("./age_deploy/home");
+    write_file("./age_deploy/templates/netrc.age", b"password hunter2\n", 0o600).unwrap();
+    fs::File::create("./age_deploy/broken.age")
+        .and_then(|mut f| f.write_all(b"not encrypted"))
+        .unwrap();
+
+    let mut root = create_tree_from_path("./age_deploy/templates", "./age_deploy/home").unwrap();
+    let saved = root.save("./age_deploy/backup");
+    let deployed = crypt::read_file("./age_deploy/home/netrc");
+    let broken_diff = create_template_tree("./age_deploy/home/netrc", "./age_deploy/broken.age")
+        .and_then(|root| root.diff());
+
+    let _ = fs::remove_dir_all("./age_deploy/");
+
+    assert!(saved.is_ok());
+    assert_eq!(deployed.unwrap(), b"password hunter2\n");
+    assert!(broken_diff.is_err());
+}
+
+#[test]
+fn only_linked_dotfiles_replace_directories() {
+    let _ = fs::create_dir_all("./link_dir/home/config");
+    let _ = fs::create_dir_all("./link_dir/templates");
+    fs::File::create("./link_dir/home/config/file").unwrap();
+    unix::fs::symlink("/tmp", "./link_dir/templates/config").unwrap();
+
+    let mut inner = create_tree_from_path("./link_dir/templates", "./link_dir/home").unwrap();
+    let inner_saved = inner.save("./link_dir/backup");
+    let mut link = create_link("./link_dir/templates", "./link_dir/home/config").unwrap();
+    let link_saved = link.save("./link_dir/backup");
+    let linked = fs::symlink_metadata("./link_dir/home/config").map(|m| m.file_type().is_symlink());
+    let target_path = common::resolve_path("./link_dir/home/config", None).unwrap();
+    let backed_up = fs::metadata(["./link_dir/backup", &target_path, "/file"].concat()).is_ok();
+    let restored = link.restore();
+    let file_restored = fs::metadata("./link_dir/home/config/file").is_ok();
+
+    let _ = fs::remove_dir_all("./link_dir/");
+
+    assert!(inner_saved.is_err());
+    assert_eq!(link_saved.unwrap(), 1);
+    assert!(linked.unwrap());
+    assert!(backed_up);
+    assert!(restored.is_ok());
+    assert!(file_restored);
+}
+
+// Nested files belong to their own directory only, the walk used to list them for every parent
+#[test]
+fn nested_files_appear_once() {
+    let _ = fs::create_dir_all("./nested_tree/src/sub");
+    fs::File::create("./nested_tree/src/sub/file")
+        .and_then(|mut f| f.write_all(b"x\n"))
+        .unwrap();
+
+    let mut root = create_tree_from_path("./nested_tree/src", "./nested_tree/tar").unwrap();
+    let diff = root.diff();
+    let changed = root.save("./nested_tree/backup");
+    let changes = root.changes().len();
+
+    let _ = fs::remove_dir_all("./nested_tree/");
+
+    assert_eq!(diff.unwrap().matches("sub/file (rendered)").count(), 1);
+    assert_eq!(changed.unwrap(), 3);
+    assert_eq!(changes, 3);
+}
@@ -0,0 +1,297 @@
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::os::unix::fs::PermissionsExt;
+use std::{fs, path};
+use toml;
+
+use filesystem;
+use common;
+use error;
+
+// Directory below the root of dry runs which contains their generations
+const STATE_DIR: &'static str = ".dotfiler";
+
+// Every deployment stores its backups in a new numbered generation
+pub struct Generation {
+    path: String,
+    manifest: Manifest,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    timestamp: u64,
+    files: Vec<filesystem::Change>,
+}
+
+impl Generation {
+    // Deployments below another root like "./dry/" keep their generations in that root,
+    // so they can never be rolled back onto the real files
+    pub fn new(target_path: &str) -> Result<Generation, error::DotfilerError> {
+        let generations_path = if target_path == "/" {
+            get_generations_path()?
+        } else {
+            [target_path, STATE_DIR, "/generations"].concat()
+        };
+
+        let number = get_generation_numbers(&generations_path)?.last().map_or(1, |n| n + 1);
+        let path = [&generations_path, "/", &number.to_string()].concat();
+
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0,
+        };
+
+        Ok(Generation {
+               path: path,
+               manifest: Manifest {
+                   timestamp: timestamp,
+                   files: Vec::new(),
+               },
+           })
+    }
+
+    // Location where backups of the overwritten files are stored
+    pub fn backup_path(&self) -> String {
+        [&self.path, "/files"].concat()
+    }
+
+    // The manifest is written right away, so a run which is interrupted can still be rolled back
+    pub fn add_changes(&mut self, mut changes: Vec<filesystem::Change>) {
+        if changes.is_empty() {
+            return;
+        }
+
+        self.manifest.files.append(&mut changes);
+        if let Err(e) = self.write_manifest() {
+            println!("Unable to record the changes in '{}':\n{}", self.path, e);
+        }
+    }
+
+    // Write the list of changed files, generations without changes are not stored
+    pub fn finish(self) -> Result<(), error::DotfilerError> {
+        if self.manifest.files.is_empty() {
+            let _ = fs::remove_dir_all(&self.path);
+            return Ok(());
+        }
+
+        self.write_manifest()
+    }
+
+    fn write_manifest(&self) -> Result<(), error::DotfilerError> {
+        fs::create_dir_all(&self.path)?;
+        let manifest = toml::to_string(&self.manifest)?;
+        fs::File::create([&self.path, "/generation.toml"].concat())
+            .and_then(|mut f| f.write_all(manifest.as_bytes()))?;
+
+        Ok(())
+    }
+}
+
+pub fn list() -> Result<(), error::DotfilerError> {
+    let numbers = get_recorded_numbers(&get_generations_path()?)?;
+    if numbers.is_empty() {
+        println!("There are no generations yet.");
+        return Ok(());
+    }
+
+    for number in numbers {
+        let manifest = load_manifest(number)?;
+        println!("{:>4}  {}  {} files",
+                 number,
+                 format_timestamp(manifest.timestamp),
+                 manifest.files.len());
+
+        for file in &manifest.files {
+            let action = if file.existed_already {
+                "changed"
+            } else {
+                "created"
+            };
+            println!("        {} {}", action, file.target_path);
+        }
+    }
+
+    Ok(())
+}
+
+// Restore every file changed by a generation, the latest generation is used by default
+pub fn rollback(number: Option<usize>) -> Result<(), error::DotfilerError> {
+    let number = match number {
+        Some(number) => number,
+        None => {
+            get_recorded_numbers(&get_generations_path()?)?
+                .last()
+                .cloned()
+                .ok_or_else(|| String::from("There are no generations to roll back."))?
+        }
+    };
+
+    let manifest = load_manifest(number)?;
+    let backup_path = [&get_generations_path()?, "/", &number.to_string(), "/files"].concat();
+
+    // Undo the changes in reverse order, so created directories are empty before removing them
+    let mut errors = Vec::new();
+    for file in manifest.files.iter().rev() {
+        if let Err(e) = restore_file(file, &backup_path) {
+            errors.push(format!("Unable to restore '{}': {}", file.target_path, e));
+        }
+    }
+
+    if !errors.is_empty() {
+        let msg = format!("Generation {} has not been restored completely:\n{}",
+                          number,
+                          errors.join("\n"));
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    println!("Successfully restored generation {}.", number);
+    Ok(())
+}
+
+fn restore_file(file: &filesystem::Change, backup_path: &str) -> Result<(), error::DotfilerError> {
+    let target_path = &file.target_path;
+
+    // Directories which only had their mode changed are kept, together with their content
+    if let Some(mode) = file.mode {
+        fs::set_permissions(target_path, fs::Permissions::from_mode(mode))?;
+        return Ok(());
+    }
+
+    if let Ok(meta) = fs::symlink_metadata(target_path) {
+        if meta.is_dir() {
+            fs::remove_dir(target_path)?;
+        } else {
+            fs::remove_file(target_path)?;
+        }
+    }
+
+    if file.existed_already {
+        filesystem::copy_recursive(&[backup_path, target_path].concat(), target_path)?;
+    }
+
+    Ok(())
+}
+
+fn get_generations_path() -> Result<String, error::DotfilerError> {
+    Ok([&common::get_state_path()?, "/generations"].concat())
+}
+
+// Numbers of the generations which have a manifest, in ascending order
+// Runs which have been killed before changing anything leave directories without one
+fn get_recorded_numbers(generations_path: &str) -> Result<Vec<usize>, error::DotfilerError> {
+    Ok(get_generation_numbers(generations_path)?
+           .into_iter()
+           .filter(|number| {
+                       let manifest_path = get_manifest_path(generations_path, *number);
+                       path::Path::new(&manifest_path).exists()
+                   })
+           .collect())
+}
+
+// All existing generation numbers in ascending order, new generations must not reuse any
+fn get_generation_numbers(generations_path: &str) -> Result<Vec<usize>, error::DotfilerError> {
+    if !path::Path::new(generations_path).exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut numbers: Vec<usize> = fs::read_dir(generations_path)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_string_lossy().parse().ok())
+        .collect();
+    numbers.sort();
+
+    Ok(numbers)
+}
+
+fn get_manifest_path(generations_path: &str, number: usize) -> String {
+    [generations_path, "/", &number.to_string(), "/generation.toml"].concat()
+}
+
+fn load_manifest(number: usize) -> Result<Manifest, error::DotfilerError> {
+    let manifest_path = get_manifest_path(&get_generations_path()?, number);
+
+    let mut buffer = String::new();
+    match fs::File::open(&manifest_path) {
+        Ok(mut f) => f.read_to_string(&mut buffer)?,
+        Err(_) => return Err(format!("Generation {} does not exist.", number))?,
+    };
+
+    Ok(toml::from_str(&buffer)?)
+}
+
+// Format seconds since the epoch as UTC date and time
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+
+    // Convert days since the epoch to the civil date
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 -
+                       day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60)
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn format_timestamp_as_utc_date() {
+    assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+    assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+    assert_eq!(format_timestamp(1_492_423_445), "2017-04-17 10:04:05 UTC");
+}
+
+#[test]
+fn generations_of_dry_runs_stay_below_their_root() {
+    let mut generation = Generation::new("./generations_dry/").unwrap();
+    generation.add_changes(vec![filesystem::Change {
+                                    target_path: String::from("./generations_dry/x"),
+                                    existed_already: false,
+                                    mode: None,
+                                }]);
+    generation.finish().unwrap();
+
+    let recorded = fs::metadata("./generations_dry/.dotfiler/generations/1/generation.toml")
+        .is_ok();
+    let _ = fs::remove_dir_all("./generations_dry/");
+
+    assert!(recorded);
+}
+
+#[test]
+fn interrupted_generations_are_recorded_or_skipped() {
+    let generations_path = "./generations_partial/.dotfiler/generations";
+    let _ = fs::create_dir_all([generations_path, "/1/files"].concat());
+
+    // The manifest exists without calling "finish", like in a run which has been killed
+    let mut generation = Generation::new("./generations_partial/").unwrap();
+    generation.add_changes(vec![filesystem::Change {
+                                    target_path: String::from("./generations_partial/x"),
+                                    existed_already: false,
+                                    mode: None,
+                                }]);
+    let recorded = get_recorded_numbers(generations_path);
+    let next = Generation::new("./generations_partial/").map(|g| g.path);
+
+    let _ = fs::remove_dir_all("./generations_partial/");
+
+    assert_eq!(recorded.unwrap(), vec![2]);
+    assert_eq!(next.unwrap(), [generations_path, "/3"].concat());
+}
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::{fs, path};
+use std::io::Read;
+use handlebars::{Handlebars, Helper, RenderContext, RenderError};
+use walkdir;
+
+use error;
+
+// Directory inside "templates/" which contains partials instead of dotfiles
+pub const PARTIALS_DIR: &'static str = "_partials";
+
+// Handlebars instance with all helpers used for rendering templates
+pub fn create_handlebars() -> Handlebars {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_helper("rgb", Box::new(rgb));
+    handlebars.register_helper("rgb_float", Box::new(rgb_float));
+    handlebars.register_helper("hex_0x", Box::new(hex_0x));
+    handlebars.register_helper("strip_hash", Box::new(strip_hash));
+    handlebars.register_helper("alpha", Box::new(alpha));
+    handlebars.register_helper("lighten", Box::new(lighten));
+    handlebars.register_helper("darken", Box::new(darken));
+    handlebars
+}
+
+// Register every file in "templates/_partials/" as partial, named after its path without extension
+// "_partials/i3/keys.conf" can be used as "{{> i3/keys}}"
+// Returns the path and content of every partial by its name
+pub fn register_partials(handlebars: &mut Handlebars,
+                         templates_path: &str)
+                         -> Result<BTreeMap<String, (String, String)>, error::DotfilerError> {
+    let partials_path = [templates_path, "/", PARTIALS_DIR].concat();
+
+    let mut partials = BTreeMap::new();
+    for (path, content) in read_partials(templates_path)? {
+        let name = path::Path::new(&path)
+            .strip_prefix(&partials_path)
+            .unwrap_or(path::Path::new(&path))
+            .with_extension("")
+            .to_string_lossy()
+            .to_string();
+
+        if let Err(e) = handlebars.register_partial(&name, &content) {
+            let msg = format!("Unable to load the partial '{}':\n{}", path, e);
+            return Err(error::DotfilerError::Message(msg));
+        }
+        partials.insert(name, (path, content));
+    }
+
+    Ok(partials)
+}
+
+// Path and content of every partial
+pub fn read_partials(templates_path: &str) -> Result<Vec<(String, String)>, error::DotfilerError> {
+    let partials_path = [templates_path, "/", PARTIALS_DIR].concat();
+
+    let mut partials = Vec::new();
+    for entry in walkdir::WalkDir::new(&partials_path).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_file() {
+            let mut content = String::new();
+            fs::File::open(entry.path())?.read_to_string(&mut content)?;
+            partials.push((entry.path().to_string_lossy().to_string(), content));
+        }
+    }
+
+    Ok(partials)
+}
+
+#[derive(Debug, PartialEq)]
+struct Color {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+impl Color {
+    // Parse "#rrggbb" or "#rgb", the "#" is optional
+    fn parse(color: &str) -> Option<Color> {
+        let hex = color.trim_matches('#');
+        let hex = match hex.len() {
+            6 => hex.to_string(),
+            3 => hex.chars().flat_map(|c| vec![c, c]).collect(),
+            _ => return None,
+        };
+
+        let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some(Color {
+                 r: component(0)?,
+                 g: component(2)?,
+                 b: component(4)?,
+             })
+    }
+
+    // Move every component towards "target" by "amount" between 0 and 1
+    fn mix(&self, target: u8, amount: f64) -> Color {
+        let amount = amount.max(0.).min(1.);
+        let mix = |c: u8| (c as f64 + (target as f64 - c as f64) * amount).round() as u8;
+        Color {
+            r: mix(self.r),
+            g: mix(self.g),
+            b: mix(self.b),
+        }
+    }
+
+    fn to_hex(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+// {{rgb fg}} -> "rgb(255, 255, 255)"
+fn rgb(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let color = color_param(h)?;
+    write(rc, &format!("rgb({}, {}, {})", color.r, color.g, color.b))
+}
+
+// {{rgb_float fg}} -> "1.000,0.500,0.000"
+fn rgb_float(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let color = color_param(h)?;
+    write(rc,
+          &format!("{:.3},{:.3},{:.3}",
+                   color.r as f64 / 255.,
+                   color.g as f64 / 255.,
+                   color.b as f64 / 255.))
+}
+
+// {{hex_0x fg}} -> "0xffffff"
+fn hex_0x(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let color = color_param(h)?;
+    write(rc, &["0x", &color.to_hex()].concat())
+}
+
+// {{strip_hash fg}} -> "ffffff"
+fn strip_hash(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let color = color_param(h)?;
+    write(rc, &color.to_hex())
+}
+
+// {{alpha fg 0.8}} -> "rgba(255, 255, 255, 0.8)"
+fn alpha(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let color = color_param(h)?;
+    let alpha = amount_param(h)?.max(0.).min(1.);
+    write(rc,
+          &format!("rgba({}, {}, {}, {})", color.r, color.g, color.b, alpha))
+}
+
+// {{lighten fg 0.1}} -> fg mixed with 10% white
+fn lighten(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let color = color_param(h)?.mix(255, amount_param(h)?);
+    write(rc, &["#", &color.to_hex()].concat())
+}
+
+// {{darken fg 0.1}} -> fg mixed with 10% black
+fn darken(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> Result<(), RenderError> {
+    let color = color_param(h)?.mix(0, amount_param(h)?);
+    write(rc, &["#", &color.to_hex()].concat())
+}
+
+fn color_param(h: &Helper) -> Result<Color, RenderError> {
+    let param = h.param(0)
+        .ok_or_else(|| RenderError::new(format!("Missing color for helper \"{}\".", h.name())))?;
+
+    param.value()
+        .as_str()
+        .and_then(Color::parse)
+        .ok_or_else(|| {
+                        RenderError::new(format!("Invalid color {} for helper \"{}\".",
+                                                 param.value(),
+                                                 h.name()))
+                    })
+}
+
+fn amount_param(h: &Helper) -> Result<f64, RenderError> {
+    h.param(1)
+        .and_then(|param| param.value().as_f64())
+        .ok_or_else(|| RenderError::new(format!("Missing amount for helper \"{}\".", h.name())))
+}
+
+fn write(rc: &mut RenderContext, output: &str) -> Result<(), RenderError> {
+    rc.writer.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn parse_short_and_long_colors() {
+    assert_eq!(Color::parse("#ff8000"),
+               Some(Color {
+                        r: 255,
+                        g: 128,
+                        b: 0,
+                    }));
+    assert_eq!(Color::parse("f80"),
+               Some(Color {
+                        r: 255,
+                        g: 136,
+                        b: 0,
+                    }));
+    assert_eq!(Color::parse("#ff80"), None);
+    assert_eq!(Color::parse("#gg0000"), None);
+}
+
+#[test]
+fn render_color_helpers() {
+    let handlebars = create_handlebars();
+    let mut variables = ::std::collections::BTreeMap::new();
+    variables.insert("fg", "#ff8000");
+
+    let render = |template: &str| handlebars.template_render(template, &variables).unwrap();
+    assert_eq!(render("{{rgb fg}}"), "rgb(255, 128, 0)");
+    assert_eq!(render("{{rgb_float fg}}"), "1.000,0.502,0.000");
+    assert_eq!(render("{{hex_0x fg}}"), "0xff8000");
+    assert_eq!(render("{{strip_hash fg}}"), "ff8000");
+    assert_eq!(render("{{alpha fg 0.8}}"), "rgba(255, 128, 0, 0.8)");
+    assert_eq!(render("{{lighten fg 0.5}}"), "#ffc080");
+    assert_eq!(render("{{darken fg 0.5}}"), "#804000");
+}
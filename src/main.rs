@@ -6,18 +6,27 @@ extern crate rusqlite;
 extern crate walkdir;
 extern crate clap;
 extern crate toml;
+extern crate age;
 
-use std::fs;
+use std::{fs, process};
 
 mod add_template;
+mod check;
+mod crypt;
+mod diff;
 mod filesystem;
+mod generations;
+mod helpers;
 mod templates;
+mod pull;
+mod renderer;
 mod scripts;
+mod secrets;
+mod status;
+mod system;
 mod common;
 mod error;
 
-// TODO: Check for diffs instead of overwriting every file
-// TODO: Add ability to add script through command
 fn main() {
     let args = clap::App::new("Dotfiler")
         .version("0.1.0")
@@ -27,6 +36,27 @@ fn main() {
             .short("d")
             .long("dry")
             .help("Copy the files to the './dry/' directory instead of replacing the originals."))
+        .arg(clap::Arg::with_name("profile")
+            .short("p")
+            .long("profile")
+            .help("The profile whose variables are used. The default is the current hostname.")
+            .value_name("NAME")
+            .global(true))
+        .arg(clap::Arg::with_name("lenient")
+            .long("lenient")
+            .help("Render undefined variables as empty String instead of failing.")
+            .global(true))
+        .arg(clap::Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Print additional information, like why dotfiles have been skipped."))
+        .arg(clap::Arg::with_name("atomic")
+            .short("a")
+            .long("atomic")
+            .help("Render every template before saving and roll back all files on failure."))
+        .arg(clap::Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .help("Stop executing scripts after the first one failed."))
         .arg(clap::Arg::with_name("config")
             .short("c")
             .long("config")
@@ -53,7 +83,91 @@ fn main() {
                 .value_name("FILE"))
             .arg(clap::Arg::with_name("no-templating")
                 .long("no-templating")
-                .help("Do not replace Strings in the files with matching variables from config.")))
+                .help("Do not replace Strings in the files with matching variables from config."))
+            .arg(clap::Arg::with_name("encrypt")
+                .long("encrypt")
+                .help("Store the template encrypted with the local key file.")))
+        .subcommand(clap::SubCommand::with_name("add-script")
+            .about("Add a new script which is executed after the dotfiles have been deployed.")
+            .version("0.1.0")
+            .author("Christian Dürr <contact@christianduerr>")
+            .arg(clap::Arg::with_name("FILE")
+                .help("Script you want to add.")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("name")
+                .short("n")
+                .long("name")
+                .value_name("NAME")
+                .help("An alternative name for the new script."))
+            .arg(clap::Arg::with_name("trigger")
+                .short("t")
+                .long("trigger")
+                .value_name("TEMPLATE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only run the script if a matching template changed. Supports '*' and '?'."))
+            .arg(clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("An alternative location for the config file. The default is \
+                       './config.toml'")
+                .value_name("FILE")))
+        .subcommand(clap::SubCommand::with_name("diff")
+            .about("Show the differences between your templates and the current dotfiles.")
+            .version("0.1.0")
+            .author("Christian Dürr <contact@christianduerr>")
+            .arg(clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("An alternative location for the config file. The default is \
+                       './config.toml'")
+                .value_name("FILE")))
+        .subcommand(clap::SubCommand::with_name("status")
+            .about("Show which dotfiles are out of sync with their templates.")
+            .version("0.1.0")
+            .author("Christian Dürr <contact@christianduerr>")
+            .arg(clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("An alternative location for the config file. The default is \
+                       './config.toml'")
+                .value_name("FILE")))
+        .subcommand(clap::SubCommand::with_name("check")
+            .about("Check the config and templates for errors without writing anything.")
+            .version("0.1.0")
+            .author("Christian Dürr <contact@christianduerr>")
+            .arg(clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("An alternative location for the config file. The default is \
+                       './config.toml'")
+                .value_name("FILE")))
+        .subcommand(clap::SubCommand::with_name("pull")
+            .about("Update your templates with changes made to the dotfiles.")
+            .version("0.1.0")
+            .author("Christian Dürr <contact@christianduerr>")
+            .arg(clap::Arg::with_name("NAME")
+                .help("Templates which should be updated. The default is every template.")
+                .multiple(true)
+                .index(1))
+            .arg(clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("An alternative location for the config file. The default is \
+                       './config.toml'")
+                .value_name("FILE")))
+        .subcommand(clap::SubCommand::with_name("generations")
+            .about("List all backup generations and the files they changed.")
+            .version("0.1.0")
+            .author("Christian Dürr <contact@christianduerr>"))
+        .subcommand(clap::SubCommand::with_name("rollback")
+            .about("Restore all files changed by a generation.")
+            .version("0.1.0")
+            .author("Christian Dürr <contact@christianduerr>")
+            .arg(clap::Arg::with_name("GENERATION")
+                .help("Number of the generation to restore. The default is the latest one.")
+                .index(1)))
         .get_matches();
 
     if let Some(args) = args.subcommand_matches("add") {
@@ -61,23 +175,121 @@ fn main() {
         let config_path = get_config_dir(args.value_of("config"));
         let templating_enabled = !args.is_present("no-templating");
         let new_name = args.value_of("name");
+        let encrypt = args.is_present("encrypt");
 
-        let result = add_template::add_template(&config_path, file, new_name, templating_enabled);
+        let result = add_template::add_template(&config_path,
+                                                file,
+                                                new_name,
+                                                templating_enabled,
+                                                encrypt);
         if let Err(e) = result {
             println!("{}", e);
         }
+    } else if let Some(args) = args.subcommand_matches("add-script") {
+        let file = args.value_of("FILE").unwrap();
+        let config_path = get_config_dir(args.value_of("config"));
+        let new_name = args.value_of("name");
+        let triggers: Vec<&str> = match args.values_of("trigger") {
+            Some(triggers) => triggers.collect(),
+            None => Vec::new(),
+        };
+
+        if let Err(e) = add_template::add_script(&config_path, file, new_name, &triggers) {
+            println!("{}", e);
+        }
+    } else if let Some(args) = args.subcommand_matches("diff") {
+        let config_path = get_config_dir(args.value_of("config"));
+
+        if let Err(e) = diff::print(&config_path,
+                                    args.value_of("profile"),
+                                    !args.is_present("lenient")) {
+            println!("{}", e);
+        }
+    } else if let Some(args) = args.subcommand_matches("status") {
+        let config_path = get_config_dir(args.value_of("config"));
+
+        // Exit with an error if anything is out of sync, this allows usage in scripts
+        match status::print(&config_path,
+                            args.value_of("profile"),
+                            !args.is_present("lenient")) {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                println!("{}", e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(args) = args.subcommand_matches("check") {
+        let config_path = get_config_dir(args.value_of("config"));
+
+        // Exit with an error if anything is wrong, this allows usage as pre-commit hook
+        match check::print(&config_path, args.value_of("profile")) {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                println!("{}", e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(args) = args.subcommand_matches("pull") {
+        let config_path = get_config_dir(args.value_of("config"));
+        let names: Vec<&str> = match args.values_of("NAME") {
+            Some(names) => names.collect(),
+            None => Vec::new(),
+        };
+
+        if let Err(e) = pull::pull(&config_path, &names, args.value_of("profile")) {
+            println!("{}", e);
+        }
+    } else if args.subcommand_matches("generations").is_some() {
+        if let Err(e) = generations::list() {
+            println!("{}", e);
+        }
+    } else if let Some(args) = args.subcommand_matches("rollback") {
+        let number = match args.value_of("GENERATION").map(|n| n.parse()) {
+            Some(Ok(number)) => Some(number),
+            Some(Err(_)) => {
+                println!("The generation has to be a number.");
+                process::exit(1);
+            }
+            None => None,
+        };
+
+        if let Err(e) = generations::rollback(number) {
+            println!("{}", e);
+        }
     } else {
         let config_path = get_config_dir(args.value_of("config"));
-        let root_path = if args.is_present("dry") {
+        let dry = args.is_present("dry");
+        let root_path = if dry {
             [&common::get_working_dir().unwrap(), "/dry/"].concat()
         } else {
             String::from("/")
         };
 
-        if let Err(e) = templates::load(&root_path, &config_path) {
-            println!("{}", e);
-        } else if let Err(e) = scripts::execute(&config_path) {
-            println!("{}", e);
+        let profile = args.value_of("profile");
+        let atomic = args.is_present("atomic");
+        let strict = !args.is_present("lenient");
+        let verbose = args.is_present("verbose");
+        let fail_fast = args.is_present("fail-fast");
+
+        let result = templates::load(&root_path, &config_path, profile, atomic, strict, verbose);
+        let deployment = match result {
+            Ok(deployment) => deployment,
+            Err(e) => {
+                println!("{}", e);
+                process::exit(1);
+            }
+        };
+
+        // Exit with an error if any template or script failed
+        match scripts::execute(&config_path, profile, dry, &deployment.changed, fail_fast) {
+            Ok(true) if deployment.failed == 0 => (),
+            Ok(_) => process::exit(1),
+            Err(e) => {
+                println!("{}", e);
+                process::exit(1);
+            }
         }
     }
 }
@@ -0,0 +1,153 @@
+use std::io;
+use toml::value;
+
+use generations;
+use filesystem;
+use templates;
+use secrets;
+use common;
+use error;
+
+// Update the templates with the current content of their targets
+// If "names" is empty, every dotfile is pulled
+pub fn pull(config_path: &str,
+            names: &[&str],
+            profile: Option<&str>)
+            -> Result<(), error::DotfilerError> {
+    let config = common::load_config(config_path)?;
+    let templates_path = common::get_templates_path(config_path)?.to_string_lossy().to_string();
+    let variables = common::get_variables(&config, profile)?;
+    let unresolved = common::get_merged_variables(&config, profile)?
+        .unwrap_or_else(value::Table::new);
+
+    let dotfiles = templates::get_active_dotfiles(&config, false);
+
+    for name in names {
+        if !dotfiles.iter().any(|d| d.template == *name) {
+            println!("There is no dotfile with the template '{}'.", name);
+        }
+    }
+
+    // Backups of the old templates are stored like every other deployment
+    let mut generation = generations::Generation::new("/")?;
+    let backup_path = generation.backup_path();
+
+    for dotfile in dotfiles {
+        if !names.is_empty() && !names.contains(&dotfile.template.as_str()) {
+            continue;
+        }
+
+        // Errors only skip this dotfile, the generation still has to be finished
+        match common::is_linked(dotfile) {
+            Ok(false) => (),
+            Ok(true) => {
+                println!("The template '{}' is linked and always up to date.", dotfile.template);
+                continue;
+            }
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        }
+
+        let paths = common::resolve_path(&dotfile.template, Some(&templates_path))
+            .map_err(error::DotfilerError::from)
+            .and_then(|template_path| {
+                          Ok((template_path, templates::get_target_path(dotfile, "/")?))
+                      });
+        let (template_path, tar_path) = match paths {
+            Ok(paths) => paths,
+            Err(e) => {
+                println!("Unable to pull the template '{}':\n{}", dotfile.template, e);
+                continue;
+            }
+        };
+
+        // The live file is the source and the template is the target this time
+        let mut root = match filesystem::create_template_tree(&tar_path, &template_path) {
+            Ok(root) => root,
+            Err(e) => {
+                println!("Can't create tree for file '{}':\n{}", tar_path, e);
+                continue;
+            }
+        };
+
+        // Secrets are never turned into placeholders, so they would end up in the template
+        // Only the secrets used by the template are resolved, others can't be in the file
+        let references = match templates::get_references(dotfile, &templates_path) {
+            Ok(references) => references,
+            Err(e) => {
+                println!("Unable to read the template '{}':\n{}", template_path, e);
+                continue;
+            }
+        };
+        let secrets = match secrets::resolve_referenced(&mut unresolved.clone(), &references) {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                println!("Unable to pull the template '{}':\n{}", dotfile.template, e);
+                continue;
+            }
+        };
+
+        // Checked before templating, placeholders could hide parts of a secret otherwise
+        match root.diff() {
+            Ok(ref diff) if secrets.is_contained_in(diff) => {
+                println!("The file '{}' contains a secret and can't be pulled.", tar_path);
+                continue;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                println!("Unable to compare '{}' with its template:\n{}", tar_path, e);
+                continue;
+            }
+        }
+
+        if let Some(ref vars) = variables {
+            if let Err(e) = root.template(vars) {
+                println!("Unable to template the file '{}':\n{}", tar_path, e);
+                continue;
+            }
+        }
+
+        let diff = match root.diff() {
+            Ok(ref diff) if diff.is_empty() => {
+                println!("The template '{}' is up to date.", dotfile.template);
+                continue;
+            }
+            Ok(diff) => diff,
+            Err(e) => {
+                println!("Unable to compare '{}' with its template:\n{}", tar_path, e);
+                continue;
+            }
+        };
+
+        print!("{}", diff);
+        println!("Do you want to update the template '{}'? [y/N]", dotfile.template);
+
+        let mut buf = String::new();
+        if let Err(e) = io::stdin().read_line(&mut buf) {
+            println!("Unable to read the answer:\n{}", e);
+            continue;
+        }
+        if buf.to_lowercase().trim() != "y" {
+            println!("The template has not been updated.");
+            continue;
+        }
+
+        match root.save(&backup_path) {
+            Ok(_) => {
+                generation.add_changes(root.changes());
+                println!("Successfully updated the template '{}'.", dotfile.template);
+            }
+            Err(e) => {
+                println!("Unable to update the template '{}':\n{}", dotfile.template, e);
+
+                if let Err(e) = root.restore() {
+                    println!("Critical Error! Unable to recover from failure.\n{}", e);
+                }
+            }
+        }
+    }
+
+    generation.finish()
+}
@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, BTreeSet};
+use handlebars::Handlebars;
+use toml::value;
+
+use helpers;
+use error;
+
+// Handlebars with all helpers and partials, shared by every template of a run
+pub struct Renderer {
+    pub handlebars: Handlebars,
+    // Fail on undefined variables instead of rendering them as empty String
+    pub strict: bool,
+    // Path and content of every partial by its name
+    partials: BTreeMap<String, (String, String)>,
+}
+
+impl Renderer {
+    pub fn new(templates_path: &str, strict: bool) -> Result<Renderer, error::DotfilerError> {
+        let mut handlebars = helpers::create_handlebars();
+        let partials = helpers::register_partials(&mut handlebars, templates_path)?;
+
+        Ok(Renderer {
+               handlebars: handlebars,
+               strict: strict,
+               partials: partials,
+           })
+    }
+
+    // Render a template, "source" is the file the template is from
+    pub fn render(&self,
+                  template: &str,
+                  context: &value::Table,
+                  source: &str)
+                  -> Result<String, error::DotfilerError> {
+        if self.strict {
+            let mut undefined = Vec::new();
+            self.find_undefined(template, context, source, &mut BTreeSet::new(), &mut undefined);
+
+            if !undefined.is_empty() {
+                return Err(error::DotfilerError::UndefinedVariables(undefined));
+            }
+        }
+
+        Ok(self.handlebars.template_render(template, context)?)
+    }
+
+    // Find undefined variables of a template and every partial it includes
+    // "visited" contains the partials which are checked already, so recursive partials terminate
+    fn find_undefined(&self,
+                      template: &str,
+                      context: &value::Table,
+                      source: &str,
+                      visited: &mut BTreeSet<String>,
+                      undefined: &mut Vec<String>) {
+        let (references, partials) = scan(&self.handlebars, template);
+
+        for reference in references {
+            if !reference.optional && !is_defined(&reference.name, context) {
+                undefined.push(format!("{}:{}:{}: Undefined variable '{}'",
+                                       source,
+                                       reference.line,
+                                       reference.column,
+                                       reference.name));
+            }
+        }
+
+        for name in partials {
+            if !visited.insert(name.clone()) {
+                continue;
+            }
+
+            if let Some(&(ref path, ref content)) = self.partials.get(&name) {
+                self.find_undefined(content, context, path, visited, undefined);
+            }
+        }
+    }
+}
+
+// A variable used by a template
+pub struct Reference {
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+    // Parameters of "if" and "unless" are allowed to be undefined
+    pub optional: bool,
+}
+
+// Find every variable used by a template
+// Variables inside "each" and "with" blocks are relative to the block, so they are skipped
+pub fn get_references(handlebars: &Handlebars, template: &str) -> Vec<Reference> {
+    scan(handlebars, template).0
+}
+
+// Find every variable and every partial used by a template
+// Partials with parameters or inside blocks have a different context, so they are skipped
+fn scan(handlebars: &Handlebars, template: &str) -> (Vec<Reference>, Vec<String>) {
+    let mut references = Vec::new();
+    let mut partials = Vec::new();
+    let mut blocks: Vec<String> = Vec::new();
+
+    let mut offset = 0;
+    while let Some(start) = template[offset..].find("{{").map(|start| start + offset) {
+        let end = match template[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        offset = end + 2;
+
+        let expression = template[start + 2..end]
+            .trim_matches(|c| c == '{' || c == '}' || c == '~')
+            .trim();
+        let line = template[..start].matches('\n').count() + 1;
+        let column = start - template[..start].rfind('\n').map_or(0, |i| i + 1) + 1;
+
+        if expression.starts_with('/') {
+            blocks.pop();
+            continue;
+        }
+
+        let in_scope = !blocks.iter().any(|b| b == "each" || b == "with");
+
+        if expression.starts_with('>') {
+            let tokens = tokenize(&expression[1..]);
+            if in_scope && tokens.len() == 1 && !tokens[0].1 {
+                partials.push(tokens[0].0.clone());
+            }
+            continue;
+        }
+
+        // Comments and "else" don't reference any variables
+        if expression.starts_with('!') || expression == "else" {
+            continue;
+        }
+
+        let (block, expression) = if expression.starts_with('#') || expression.starts_with('^') {
+            (true, &expression[1..])
+        } else {
+            (false, expression)
+        };
+
+        let tokens = tokenize(expression);
+        let name = tokens.first().map_or(String::new(), |&(ref token, _)| token.clone());
+
+        let optional = name == "if" || name == "unless";
+
+        let mut names = Vec::new();
+        if tokens.len() == 1 && !block {
+            // Without parameters, this is either a variable or a helper without parameters
+            if handlebars.get_helper(&name).is_none() {
+                names.push(name.clone());
+            }
+        } else {
+            for &(ref token, is_helper) in tokens.iter().skip(1) {
+                if !is_helper {
+                    names.push(token.clone());
+                }
+            }
+        }
+
+        if block {
+            blocks.push(name);
+        }
+
+        if in_scope {
+            for name in names.into_iter().filter_map(|name| variable_name(&name)) {
+                references.push(Reference {
+                                    name: name,
+                                    line: line,
+                                    column: column,
+                                    optional: optional,
+                                });
+            }
+        }
+    }
+
+    (references, partials)
+}
+
+// Split an expression into its tokens, the first token of a subexpression is a helper
+fn tokenize(expression: &str) -> Vec<(String, bool)> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quote = None;
+    let mut is_helper = false;
+
+    for c in expression.chars() {
+        match (c, quote) {
+            (_, Some(q)) if c == q => {
+                quote = None;
+                token.push(c);
+            }
+            (_, Some(_)) => token.push(c),
+            ('"', None) | ('\'', None) => {
+                quote = Some(c);
+                token.push(c);
+            }
+            ('(', None) => is_helper = true,
+            (' ', None) | ('\t', None) | ('\n', None) | (')', None) => {
+                if !token.is_empty() {
+                    tokens.push((token.clone(), is_helper));
+                    token.clear();
+                    is_helper = false;
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+
+    if !token.is_empty() {
+        tokens.push((token, is_helper));
+    }
+
+    tokens
+}
+
+// The variable a token refers to, literals and special names like "@index" are ignored
+fn variable_name(token: &str) -> Option<String> {
+    // Hash parameters like "key=value" only reference the value
+    let token = match token.find('=') {
+        Some(index) => &token[index + 1..],
+        None => token,
+    };
+
+    let first = match token.chars().next() {
+        Some(first) => first,
+        None => return None,
+    };
+
+    if first == '"' || first == '\'' || first == '@' || token.parse::<f64>().is_ok() ||
+       token.contains("../") {
+        return None;
+    }
+
+    match token {
+        "true" | "false" | "null" | "undefined" | "this" | "." => None,
+        _ if token.starts_with("this.") => Some(token[5..].to_string()),
+        _ if token.starts_with("./") => Some(token[2..].to_string()),
+        _ => Some(token.to_string()),
+    }
+}
+
+// Check if a path like "colors.fg" or "fonts.[0]" exists in the context
+pub fn is_defined(name: &str, context: &value::Table) -> bool {
+    let mut segments = name.split(|c| c == '.' || c == '/')
+        .map(|segment| segment.trim_matches(|c| c == '[' || c == ']'));
+
+    let mut current = match segments.next().and_then(|segment| context.get(segment)) {
+        Some(current) => current,
+        None => return false,
+    };
+
+    for segment in segments {
+        let next = match *current {
+            value::Value::Table(ref table) => table.get(segment),
+            value::Value::Array(ref array) => {
+                segment.parse().ok().and_then(|i: usize| array.get(i))
+            }
+            _ => None,
+        };
+
+        current = match next {
+            Some(next) => next,
+            None => return false,
+        };
+    }
+
+    true
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn references_with_position() {
+    let handlebars = helpers::create_handlebars();
+    let references = get_references(&handlebars,
+                                    "a {{ fg }}\n  {{lighten colors.bg 0.1}}{{! fgg }}\n{{#each \
+                                     fonts}}{{ name }}{{/each}}{{#if x}}{{ y }}{{/if}}");
+
+    let found: Vec<(&str, usize, usize)> = references.iter()
+        .map(|r| (r.name.as_str(), r.line, r.column))
+        .collect();
+    assert_eq!(found,
+               vec![("fg", 1, 3), ("colors.bg", 2, 3), ("fonts", 3, 1), ("x", 3, 35),
+                    ("y", 3, 44)]);
+    assert!(references[3].optional && !references[4].optional);
+}
+
+#[test]
+fn nested_variables_are_defined() {
+    let context: value::Table = ::toml::from_str("fonts = [\"a\"]\n[colors]\nfg = \"#fff\"")
+        .unwrap();
+    assert!(is_defined("colors.fg", &context));
+    assert!(is_defined("fonts.[0]", &context));
+    assert!(!is_defined("colors.bg", &context));
+    assert!(!is_defined("fonts.[1]", &context));
+}
+
+#[test]
+fn strict_mode_checks_recursive_partials() {
+    use std::io::Write;
+    use std::fs;
+
+    let _ = fs::create_dir_all("./renderer_partials/_partials");
+    fs::File::create("./renderer_partials/_partials/a")
+        .and_then(|mut f| f.write_all(b"{{ fg }}{{> b}}"))
+        .unwrap();
+    fs::File::create("./renderer_partials/_partials/b")
+        .and_then(|mut f| f.write_all(b"{{> a}}\n{{ bg }}"))
+        .unwrap();
+
+    let renderer = Renderer::new("./renderer_partials", true);
+    let context: value::Table = ::toml::from_str("fg = \"#fff\"").unwrap();
+
+    let _ = fs::remove_dir_all("./renderer_partials/");
+
+    match renderer.unwrap().render("{{> a}}{{#each x}}{{> c}}{{/each}}", &context, "t") {
+        Err(error::DotfilerError::UndefinedVariables(undefined)) => {
+            assert_eq!(undefined,
+                       vec![String::from("t:1:8: Undefined variable 'x'"),
+                            String::from("./renderer_partials/_partials/b:2:1: Undefined \
+                                          variable 'bg'")])
+        }
+        _ => panic!("The variable 'bg' of the partial 'b' is undefined."),
+    }
+}
@@ -1,43 +1,296 @@
-use std::{process, io, path};
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc;
+use std::{process, path, thread, time};
+use toml::value;
 
+use templates;
 use common;
 use error;
 
-pub fn execute(config_path: &str) -> Result<(), error::DotfilerError> {
+// Milliseconds between checks whether a running script has exited or timed out
+const POLL_INTERVAL: u64 = 50;
+
+// Run every script, their output is printed live with the script as prefix
+// Returns false if any script failed
+pub fn execute(config_path: &str,
+               profile: Option<&str>,
+               dry: bool,
+               changed: &[templates::ChangedDotfile],
+               fail_fast: bool)
+               -> Result<bool, error::DotfilerError> {
     let config = common::load_config(config_path)?;
     let scripts_path = get_scripts_path(config_path)?.to_string_lossy().to_string();
+    let environment = get_environment(&config, config_path, profile, dry, changed)?;
+
+    let scripts: Vec<&common::Script> = config.scripts
+        .iter()
+        .flat_map(|scripts| scripts.iter())
+        .filter(|script| is_triggered(script, changed))
+        .collect();
+    if scripts.is_empty() {
+        return Ok(true);
+    }
+
+    let mut executed = 0;
+    let mut failed = 0;
+    for script in &scripts {
+        if fail_fast && failed > 0 {
+            break;
+        }
+
+        executed += 1;
+        if let Err(e) = run(script, &scripts_path, &environment) {
+            println!("The script '{}' failed:\n{}", script.path(), e);
+            failed += 1;
+        }
+    }
+
+    println!("Executed {} scripts, {} failed.", executed, failed);
+    if executed < scripts.len() {
+        println!("Skipped {} scripts because of --fail-fast.", scripts.len() - executed);
+    }
+
+    Ok(failed == 0)
+}
+
+// Scripts with triggers only run if one of the matching dotfiles has changed
+fn is_triggered(script: &common::Script, changed: &[templates::ChangedDotfile]) -> bool {
+    match script.triggers() {
+        Some(triggers) => {
+            changed.iter().any(|dotfile| {
+                triggers.iter().any(|trigger| common::matches_pattern(trigger, &dotfile.template))
+            })
+        }
+        None => true,
+    }
+}
+
+// Scripts succeed based on their exit status, output on stderr alone is no failure
+fn run(script: &common::Script,
+       scripts_path: &str,
+       environment: &[(String, String)])
+       -> Result<(), error::DotfilerError> {
+    let script_path = common::resolve_path(script.path(), Some(scripts_path))?;
+
+    let mut command = process::Command::new(&script_path);
+    for &(ref key, ref val) in environment {
+        command.env(key, val);
+    }
+
+    let mut child = match command.stdin(process::Stdio::null())
+              .stdout(process::Stdio::piped())
+              .stderr(process::Stdio::piped())
+              .spawn() {
+        Ok(child) => child,
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return Err(format!("Unable to execute '{}': {}\nScripts are no longer run with \
+                                'sh', they need to be executable and start with a shebang.",
+                               script_path,
+                               e))?
+        }
+        Err(e) => return Err(format!("Unable to execute '{}': {}", script_path, e))?,
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    forward_lines(child.stdout.take(), sender.clone(), false);
+    forward_lines(child.stderr.take(), sender, true);
+
+    let prefix = format!("[{}]", script.path());
+    let poll_interval = time::Duration::from_millis(POLL_INTERVAL);
+    let start = time::Instant::now();
+
+    loop {
+        print_line(&prefix, receiver.recv_timeout(poll_interval));
+
+        if let Some(status) = child.try_wait()? {
+            // Processes started in the background can keep the output open after the exit,
+            // so only the output which is already available is printed
+            while let Ok(line) = receiver.recv_timeout(poll_interval) {
+                print_line(&prefix, Ok(line));
+            }
+
+            if status.success() {
+                return Ok(());
+            } else {
+                return Err(format!("The script exited with {}.", status))?;
+            }
+        }
+
+        if let Some(timeout) = script.timeout() {
+            if start.elapsed() >= time::Duration::from_secs(timeout) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("The script has been killed after {} seconds.", timeout))?;
+            }
+        }
+    }
+}
+
+// Everything scripts need to know about the deployment, passed as environment variables
+fn get_environment(config: &common::Config,
+                   config_path: &str,
+                   profile: Option<&str>,
+                   dry: bool,
+                   changed: &[templates::ChangedDotfile])
+                   -> Result<Vec<(String, String)>, error::DotfilerError> {
+    let config_path = common::resolve_path(config_path, None)?;
+    let config_dir = path::Path::new(&config_path).parent().unwrap();
+    let config_dir = config_dir.to_string_lossy().to_string();
+    let templates_path = common::get_templates_path(&config_path)?.to_string_lossy().to_string();
+    let active_profile = common::get_active_profile(config, profile)?.unwrap_or_default();
+    let changed: Vec<&str> = changed.iter().map(|c| c.target_path.as_str()).collect();
+
+    let dry = if dry { "1" } else { "0" };
+
+    let mut environment = vec![(String::from("DOTFILER_DRY_RUN"), String::from(dry)),
+                               (String::from("DOTFILER_CONFIG_DIR"), config_dir),
+                               (String::from("DOTFILER_TEMPLATES_DIR"), templates_path),
+                               (String::from("DOTFILER_PROFILE"), active_profile),
+                               (String::from("DOTFILER_CHANGED"), changed.join("\n"))];
+
+    // Secrets are left out, so they can't leak into the environment of every script
+    if let Some(variables) = common::get_variables(config, profile)? {
+        export_variables("DOTFILER_VAR", &variables, &mut environment);
+    }
+
+    Ok(environment)
+}
+
+// Nested tables are joined with "_", "colors.fg" becomes "DOTFILER_VAR_COLORS_FG"
+// Arrays are exported with one element per line
+fn export_variables(prefix: &str,
+                    variables: &value::Table,
+                    environment: &mut Vec<(String, String)>) {
+    for (key, val) in variables {
+        let name: String = key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        let name = [prefix, "_", &name].concat();
+
+        let val = match *val {
+            value::Value::Table(ref table) => {
+                export_variables(&name, table, environment);
+                continue;
+            }
+            value::Value::Array(ref array) => {
+                array.iter().map(to_environment_value).collect::<Vec<String>>().join("\n")
+            }
+            ref val => to_environment_value(val),
+        };
+
+        // Names like "font-size" and "font_size" end up the same, the first one is kept
+        if environment.iter().any(|&(ref existing, _)| *existing == name) {
+            println!("Warning: The variable '{}' is not exported, '{}' exists already.",
+                     key,
+                     name);
+            continue;
+        }
+        environment.push((name, val));
+    }
+}
+
+fn to_environment_value(val: &value::Value) -> String {
+    match *val {
+        value::Value::String(ref s) => s.clone(),
+        ref val => val.to_string(),
+    }
+}
+
+// Send every line of "output" to the receiving end, so stdout and stderr are printed in order
+fn forward_lines<R: Read + Send + 'static>(output: Option<R>,
+                                           sender: mpsc::Sender<(String, bool)>,
+                                           is_stderr: bool) {
+    if let Some(output) = output {
+        thread::spawn(move || for line in io::BufReader::new(output).lines() {
+                          match line {
+                              Ok(line) => {
+                                  if sender.send((line, is_stderr)).is_err() {
+                                      break;
+                                  }
+                              }
+                              Err(_) => break,
+                          }
+                      });
+    }
+}
+
+fn print_line(prefix: &str, line: Result<(String, bool), mpsc::RecvTimeoutError>) {
+    match line {
+        Ok((line, false)) => println!("{} {}", prefix, line),
+        Ok((line, true)) => {
+            let _ = writeln!(io::stderr(), "{} {}", prefix, line);
+        }
+        // Without any output left, the loop would be busy waiting
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            thread::sleep(time::Duration::from_millis(POLL_INTERVAL))
+        }
+        Err(mpsc::RecvTimeoutError::Timeout) => (),
+    }
+}
+
+// Run the "pre_deploy" or "post_deploy" command of a dotfile
+// The rendered target path is available as "$DOTFILER_TARGET"
+pub fn run_hook(command: &str, target_path: &str) -> Result<(), error::DotfilerError> {
+    let status = process::Command::new("sh")
+        .args(&["-c", command])
+        .env("DOTFILER_TARGET", target_path)
+        .status()?;
+
+    if !status.success() {
+        return Err(format!("The command '{}' failed with {}.", command, status))?;
+    }
 
-    if let Some(ref scripts) = config.scripts {
-        for script in scripts {
-            let script: String = match common::resolve_path(script, Some(&scripts_path)) {
-                Ok(path) => path,
-                Err(e) => {
-                    println!("Unable to load script '{}':\n{}", script, e);
-                    continue;
-                }
-            };
-
-            match process::Command::new("sh").args(&["-c", &script]).output() {
-                Ok(output) => {
-                    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-                    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-
-                    if !stderr.is_empty() {
-                        println!("Unable to execute script:\n{}", stderr);
-                    } else if !stdout.is_empty() {
-                        println!("Output for '{}':\n{}", script, stdout);
-                    }
-                }
-                Err(e) => println!("Unable to execute '{}':\n{}", script, e),
-            };
-        }
-    }
-
-    println!("All scripts executed.");
     Ok(())
 }
 
-fn get_scripts_path(config_path: &str) -> Result<path::PathBuf, io::Error> {
+pub fn get_scripts_path(config_path: &str) -> Result<path::PathBuf, io::Error> {
     let config_path = common::resolve_path(config_path, None)?;
     Ok(path::Path::new(&config_path).parent().unwrap().join("scripts"))
 }
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn hooks_fail_with_exit_status() {
+    assert!(run_hook("test \"$DOTFILER_TARGET\" = /tmp/.vimrc", "/tmp/.vimrc").is_ok());
+    assert!(run_hook("exit 3", "/tmp/.vimrc").is_err());
+}
+
+#[test]
+fn scripts_triggered_by_changed_templates() {
+    let changed = vec![templates::ChangedDotfile {
+                           template: String::from("polybar/config"),
+                           target_path: String::from("/home/user/.config/polybar/config"),
+                       }];
+    let config: common::Config = ::toml::from_str("[[scripts]]\npath = \"a\"\n\
+                                                   triggers = [\"polybar/*\"]\n\
+                                                   [[scripts]]\npath = \"b\"\n\
+                                                   triggers = [\"Xresources\"]\n\
+                                                   [[scripts]]\npath = \"c\"")
+            .unwrap();
+    let scripts = config.scripts.unwrap();
+
+    assert!(is_triggered(&scripts[0], &changed));
+    assert!(!is_triggered(&scripts[1], &changed));
+    assert!(is_triggered(&scripts[2], &changed));
+    assert!(!is_triggered(&scripts[0], &[]));
+}
+
+#[test]
+fn export_nested_variables() {
+    let variables: value::Table = ::toml::from_str("font-size = 11\nfont_size = 12\n\
+                                                    fonts = [\"a\", \"b\"]\n[colors]\n\
+                                                    fg = \"#fff\"")
+            .unwrap();
+    let mut environment = Vec::new();
+    export_variables("DOTFILER_VAR", &variables, &mut environment);
+
+    assert_eq!(environment,
+               vec![(String::from("DOTFILER_VAR_COLORS_FG"), String::from("#fff")),
+                    (String::from("DOTFILER_VAR_FONT_SIZE"), String::from("11")),
+                    (String::from("DOTFILER_VAR_FONTS"), String::from("a\nb"))]);
+}
@@ -0,0 +1,258 @@
+use std::io::Read;
+use std::process::Command;
+use std::fs;
+use handlebars;
+use toml::value::{Table, Value};
+
+use common;
+use error;
+
+const REDACTED: &'static str = "<redacted>";
+
+// Values of every secret variable resolved during this run
+pub struct Secrets {
+    values: Vec<String>,
+}
+
+impl Secrets {
+    // Replace every secret value in "text", so it can be printed safely
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for value in &self.values {
+            text = text.replace(value.as_str(), REDACTED);
+        }
+        text
+    }
+
+    pub fn is_contained_in(&self, text: &str) -> bool {
+        self.values.iter().any(|value| text.contains(value.as_str()))
+    }
+}
+
+// Secrets are wrapped in a "secret" table, so ordinary tables are never executed or read
+// Like "token = { secret = { command = "pass show irc" } }" or "{ secret = { file = "~/.t" } }"
+pub fn is_secret(val: &Value) -> bool {
+    match *val {
+        Value::Table(ref table) => table.len() == 1 && table.contains_key("secret"),
+        _ => false,
+    }
+}
+
+// Tables whose only key is "command" or "file" are most likely secrets without the wrapper
+// They are rejected, so the command is never rendered, exported or pulled as ordinary variable
+pub fn check_unwrapped(variables: &Table, prefix: &str) -> Result<(), error::DotfilerError> {
+    for (key, val) in variables {
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            [prefix, ".", key].concat()
+        };
+
+        let table = match *val {
+            Value::Table(ref table) if !is_secret(val) => table,
+            _ => continue,
+        };
+
+        if table.len() == 1 {
+            if let Some(kind) = table.keys().find(|k| *k == "command" || *k == "file") {
+                let msg = format!("The variable '{}' looks like a secret, secrets are written \
+                                   as '{} = {{ secret = {{ {} = \"...\" }} }}'.",
+                                  name,
+                                  key,
+                                  kind);
+                return Err(error::DotfilerError::Message(msg));
+            }
+        }
+
+        check_unwrapped(table, &name)?;
+    }
+
+    Ok(())
+}
+
+// Replace every secret in "variables" with its value
+pub fn resolve(variables: &mut Table) -> Result<Secrets, error::DotfilerError> {
+    resolve_matching(variables, &|_| true)
+}
+
+// Only resolve the secrets used by a template, so unrelated commands are never executed
+// "references" are variable names like "irc.token", using "irc" resolves every secret in it
+pub fn resolve_referenced(variables: &mut Table,
+                          references: &[String])
+                          -> Result<Secrets, error::DotfilerError> {
+    resolve_matching(variables, &|name| {
+        references.iter().any(|reference| {
+            reference == name || reference.starts_with(&[name, "."].concat()) ||
+            name.starts_with(&[reference, "."].concat())
+        })
+    })
+}
+
+fn resolve_matching(variables: &mut Table,
+                    filter: &Fn(&str) -> bool)
+                    -> Result<Secrets, error::DotfilerError> {
+    let mut values = Vec::new();
+    resolve_table(variables, "", filter, &mut values)?;
+
+    // Longer values first, so a secret contained in another one can't leave parts visible
+    values.sort_by(|a: &String, b: &String| b.len().cmp(&a.len()));
+    values.dedup();
+    Ok(Secrets { values: values })
+}
+
+fn resolve_table(variables: &mut Table,
+                 prefix: &str,
+                 filter: &Fn(&str) -> bool,
+                 values: &mut Vec<String>)
+                 -> Result<(), error::DotfilerError> {
+    for (key, val) in variables.iter_mut() {
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            [prefix, ".", key].concat()
+        };
+
+        if is_secret(val) {
+            if !filter(&name) {
+                continue;
+            }
+
+            let secret = evaluate(&name, val)?;
+            if !secret.is_empty() {
+                // "{{ secret }}" is rendered HTML-escaped, so that form has to be redacted too
+                values.push(handlebars::html_escape(&secret));
+                values.push(secret.clone());
+            }
+            *val = Value::String(secret);
+        } else if let Value::Table(ref mut table) = *val {
+            resolve_table(table, &name, filter, values)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Remove every secret, so they are never turned back into placeholders
+pub fn remove(variables: &mut Table) {
+    let secret_keys: Vec<String> = variables.iter()
+        .filter(|&(_, val)| is_secret(val))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in secret_keys {
+        variables.remove(&key);
+    }
+
+    for val in variables.values_mut() {
+        if let Value::Table(ref mut table) = *val {
+            remove(table);
+        }
+    }
+}
+
+fn evaluate(name: &str, secret: &Value) -> Result<String, error::DotfilerError> {
+    let table = match secret.get("secret") {
+        Some(&Value::Table(ref table)) if table.len() == 1 => table,
+        _ => {
+            let msg = format!("The secret '{}' needs exactly one 'command' or 'file'.", name);
+            return Err(error::DotfilerError::Message(msg));
+        }
+    };
+
+    let mut secret = if let Some(command) = table.get("command").and_then(Value::as_str) {
+        let output = Command::new("sh").arg("-c").arg(command).output()?;
+        if !output.status.success() {
+            let msg = format!("Unable to resolve the secret '{}', the command failed:\n{}",
+                              name,
+                              String::from_utf8_lossy(&output.stderr));
+            return Err(error::DotfilerError::Message(msg));
+        }
+        String::from_utf8_lossy(&output.stdout).to_string()
+    } else if let Some(path) = table.get("file").and_then(Value::as_str) {
+        let mut content = String::new();
+        fs::File::open(common::resolve_path(path, None)?)?.read_to_string(&mut content)?;
+        content
+    } else {
+        return Err(format!("The secret '{}' needs a 'command' or 'file' String.", name))?;
+    };
+
+    // Commands and files usually end with a newline which isn't part of the secret
+    while secret.ends_with('\n') || secret.ends_with('\r') {
+        secret.pop();
+    }
+
+    Ok(secret)
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn resolve_and_redact_command_secrets() {
+    let mut variables: Table = ::toml::from_str("fg = \"#fff\"\ntoken = { secret = { command = \
+                                                 \"echo hunter2\" } }\n")
+            .unwrap();
+    let secrets = resolve(&mut variables).unwrap();
+
+    assert_eq!(variables.get("token").and_then(Value::as_str), Some("hunter2"));
+    assert_eq!(secrets.redact("password=hunter2"), "password=<redacted>");
+}
+
+#[test]
+fn remove_nested_secrets() {
+    let mut variables: Table = ::toml::from_str("fg = \"#fff\"\n[irc]\nnick = \"a\"\ntoken = { \
+                                                 secret = { file = \"~/.token\" } }\n")
+            .unwrap();
+    remove(&mut variables);
+
+    let expected: Table = ::toml::from_str("fg = \"#fff\"\n[irc]\nnick = \"a\"\n").unwrap();
+    assert_eq!(variables, expected);
+}
+
+#[test]
+fn ordinary_tables_are_not_secrets() {
+    let mut variables: Table = ::toml::from_str("font = { file = \"x\", size = 9 }\n").unwrap();
+    let expected = variables.clone();
+    resolve(&mut variables).unwrap();
+    remove(&mut variables);
+
+    assert!(!is_secret(&expected["font"]));
+    assert!(check_unwrapped(&expected, "").is_ok());
+    assert_eq!(variables, expected);
+}
+
+#[test]
+fn unwrapped_secrets_are_rejected() {
+    let command: Table = ::toml::from_str("token = { command = \"pass irc\" }").unwrap();
+    let file: Table = ::toml::from_str("[irc]\ntoken = { file = \"~/.token\" }").unwrap();
+    let wrapped: Table = ::toml::from_str("token = { secret = { file = \"~/.token\" } }").unwrap();
+
+    assert!(check_unwrapped(&command, "").is_err());
+    assert!(check_unwrapped(&file, "").unwrap_err().to_string().contains("'irc.token'"));
+    assert!(check_unwrapped(&wrapped, "").is_ok());
+}
+
+#[test]
+fn redact_html_escaped_secrets() {
+    let mut variables: Table = ::toml::from_str("token = { secret = { command = \"echo 'a&b'\" } }")
+        .unwrap();
+    let secrets = resolve(&mut variables).unwrap();
+
+    assert_eq!(secrets.redact("password=a&amp;b"), "password=<redacted>");
+    assert_eq!(secrets.redact("password=a&b"), "password=<redacted>");
+    assert!(secrets.is_contained_in("a&amp;b"));
+}
+
+#[test]
+fn resolve_only_referenced_secrets() {
+    let mut variables: Table = ::toml::from_str("a = { secret = { command = \"echo a\" } }\n\
+                                                 [irc]\ntoken = { secret = { command = \"exit \
+                                                 1\" } }")
+            .unwrap();
+    let secrets = resolve_referenced(&mut variables, &[String::from("a")]).unwrap();
+
+    assert!(secrets.is_contained_in("a"));
+    assert!(is_secret(&variables["irc"]["token"]));
+}
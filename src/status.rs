@@ -0,0 +1,105 @@
+use std::{fmt, fs};
+use toml::value;
+
+use templates;
+use renderer;
+use common;
+use error;
+
+enum State {
+    InSync,
+    TargetMissing,
+    TargetDiffers,
+    TemplateMissing,
+    TypeMismatch,
+    Error(error::DotfilerError),
+}
+
+impl State {
+    fn is_in_sync(&self) -> bool {
+        match *self {
+            State::InSync => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            State::InSync => write!(f, "in sync"),
+            State::TargetMissing => write!(f, "target missing"),
+            State::TargetDiffers => write!(f, "target differs"),
+            State::TemplateMissing => write!(f, "template missing"),
+            State::TypeMismatch => write!(f, "type mismatch"),
+            State::Error(ref err) => write!(f, "error: {}", err),
+        }
+    }
+}
+
+// Returns true if every dotfile is in sync with its template
+pub fn print(config_path: &str,
+             profile: Option<&str>,
+             strict: bool)
+             -> Result<bool, error::DotfilerError> {
+    let config = common::load_config(config_path)?;
+    let templates_path = common::get_templates_path(config_path)?.to_string_lossy().to_string();
+    let dotfiles = templates::get_active_dotfiles(&config, false);
+
+    // Only the secrets used by the templates are resolved, unreadable templates are errors below
+    let references: Vec<String> = dotfiles.iter()
+        .filter_map(|dotfile| templates::get_references(dotfile, &templates_path).ok())
+        .flat_map(|references| references.into_iter())
+        .collect();
+    let (context, secrets) = common::get_referenced_context(&config, profile, &references)?;
+    let renderer = renderer::Renderer::new(&templates_path, strict)?;
+
+    let mut in_sync = true;
+    for dotfile in dotfiles {
+        let state = match get_state(dotfile, &renderer, &context, &templates_path) {
+            Ok(state) => state,
+            Err(e) => State::Error(secrets.redact(&e.to_string()).into()),
+        };
+
+        if !state.is_in_sync() {
+            in_sync = false;
+        }
+
+        println!("{:<16} {} -> {}", state.to_string(), dotfile.template, dotfile.target);
+    }
+
+    Ok(in_sync)
+}
+
+fn get_state(dotfile: &common::Dotfile,
+             renderer: &renderer::Renderer,
+             context: &value::Table,
+             templates_path: &str)
+             -> Result<State, error::DotfilerError> {
+    let template_path = common::resolve_path(&dotfile.template, Some(templates_path))?;
+    let tar_path = templates::get_target_path(dotfile, "/")?;
+
+    let template_type = match fs::symlink_metadata(&template_path) {
+        Ok(meta) => meta.file_type(),
+        Err(_) => return Ok(State::TemplateMissing),
+    };
+    let target_type = match fs::symlink_metadata(&tar_path) {
+        Ok(meta) => meta.file_type(),
+        Err(_) => return Ok(State::TargetMissing),
+    };
+
+    // Linked dotfiles are always symlinks, so their type is checked when diffing
+    let linked = common::is_linked(dotfile)?;
+    if !linked &&
+       (template_type.is_dir() != target_type.is_dir() ||
+        template_type.is_symlink() != target_type.is_symlink()) {
+        return Ok(State::TypeMismatch);
+    }
+
+    let root = templates::create_tree(dotfile, renderer, context, "/", templates_path)?;
+    if root.diff()?.is_empty() {
+        Ok(State::InSync)
+    } else {
+        Ok(State::TargetDiffers)
+    }
+}
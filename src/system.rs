@@ -0,0 +1,129 @@
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::{env, fs};
+use toml::value::{Table, Value};
+
+use common;
+
+// Only these environment variables are available, others often contain tokens or keys
+const ENV_VARIABLES: &'static [&'static str] = &["HOME",
+                                                 "USER",
+                                                 "SHELL",
+                                                 "EDITOR",
+                                                 "VISUAL",
+                                                 "PAGER",
+                                                 "BROWSER",
+                                                 "TERMINAL",
+                                                 "TERM",
+                                                 "LANG",
+                                                 "XDG_CONFIG_HOME",
+                                                 "XDG_DATA_HOME",
+                                                 "XDG_CACHE_HOME",
+                                                 "XDG_STATE_HOME",
+                                                 "XDG_RUNTIME_DIR",
+                                                 "XDG_CURRENT_DESKTOP",
+                                                 "XDG_SESSION_TYPE",
+                                                 "DISPLAY",
+                                                 "WAYLAND_DISPLAY"];
+
+// Facts about the current machine, available as "sys" while rendering templates
+// Facts which can't be determined are left out instead of failing
+pub fn get_facts() -> Table {
+    let mut facts = Table::new();
+
+    if let Ok(hostname) = common::get_hostname() {
+        facts.insert("hostname".into(), Value::String(hostname));
+    }
+    if let Some(username) = get_username() {
+        facts.insert("username".into(), Value::String(username));
+    }
+    if let Ok(home) = common::resolve_path("~", None) {
+        facts.insert("home".into(), Value::String(home));
+    }
+    if let Ok(kernel) = read_trimmed("/proc/sys/kernel/osrelease") {
+        facts.insert("kernel".into(), Value::String(kernel));
+    }
+    if let Ok(cpuinfo) = read_trimmed("/proc/cpuinfo") {
+        let cpus = cpuinfo.lines().filter(|l| l.starts_with("processor")).count();
+        facts.insert("cpus".into(), Value::Integer(cpus as i64));
+    }
+
+    facts.insert("os".into(), Value::String(env::consts::OS.into()));
+    facts.insert("arch".into(), Value::String(env::consts::ARCH.into()));
+    facts.insert("distro".into(), Value::Table(get_distro()));
+
+    let env_vars = ENV_VARIABLES.iter()
+        .filter_map(|key| env::var(key).ok().map(|val| (key.to_string(), Value::String(val))))
+        .collect();
+    facts.insert("env".into(), Value::Table(env_vars));
+
+    facts
+}
+
+// Name of the user running dotfiler, based on the owner of this process
+fn get_username() -> Option<String> {
+    if let Ok(meta) = fs::metadata("/proc/self") {
+        let uid = meta.uid().to_string();
+        if let Ok(passwd) = read_trimmed("/etc/passwd") {
+            for line in passwd.lines() {
+                let fields: Vec<&str> = line.split(':').collect();
+                if fields.len() > 2 && fields[2] == uid {
+                    return Some(fields[0].to_string());
+                }
+            }
+        }
+    }
+
+    env::var("USER").ok()
+}
+
+// Distribution details like "sys.distro.id" from "/etc/os-release"
+fn get_distro() -> Table {
+    let mut distro = Table::new();
+
+    let os_release = match read_trimmed("/etc/os-release") {
+        Ok(os_release) => os_release,
+        Err(_) => return distro,
+    };
+
+    for line in os_release.lines() {
+        let (key, val) = match line.find('=') {
+            Some(index) => (&line[..index], &line[index + 1..]),
+            None => continue,
+        };
+
+        let name = match key {
+            "ID" => "id",
+            "NAME" => "name",
+            "PRETTY_NAME" => "pretty_name",
+            "VERSION_ID" => "version",
+            _ => continue,
+        };
+        distro.insert(name.into(), Value::String(val.trim_matches('"').into()));
+    }
+
+    distro
+}
+
+fn read_trimmed(path: &str) -> Result<String, ::std::io::Error> {
+    let mut content = String::new();
+    fs::File::open(path)?.read_to_string(&mut content)?;
+    Ok(content.trim().to_string())
+}
+
+
+
+// -------------
+//     TESTS
+// -------------
+
+#[test]
+fn facts_contain_os_and_environment() {
+    let facts = get_facts();
+    assert_eq!(facts.get("os").and_then(Value::as_str), Some(env::consts::OS));
+
+    let home = env::var("HOME").unwrap();
+    let env_vars = facts.get("env").and_then(Value::as_table).unwrap();
+    assert_eq!(env_vars.get("HOME").and_then(Value::as_str), Some(home.as_str()));
+    assert!(env_vars.keys().all(|key| ENV_VARIABLES.contains(&key.as_str())));
+}
@@ -1,53 +1,414 @@
 use std::{fs, path};
+use toml::value;
 
+use generations;
+use scripts;
+use helpers;
+use renderer;
 use filesystem;
 use common;
 use error;
 
-pub fn load(target_path: &str, config_path: &str) -> Result<(), error::DotfilerError> {
+// A dotfile which has been changed while loading the templates
+pub struct ChangedDotfile {
+    pub template: String,
+    // Target path below the root directory, like the "./dry/" directory
+    pub target_path: String,
+}
+
+impl ChangedDotfile {
+    fn new(dotfile: &common::Dotfile, target_path: &str) -> ChangedDotfile {
+        ChangedDotfile {
+            template: dotfile.template.clone(),
+            target_path: get_target_path(dotfile, target_path)
+                .unwrap_or_else(|_| dotfile.target.clone()),
+        }
+    }
+}
+
+// Every dotfile which has been changed and the number of dotfiles which failed
+pub struct Deployment {
+    pub changed: Vec<ChangedDotfile>,
+    pub failed: usize,
+}
+
+pub fn load(target_path: &str,
+            config_path: &str,
+            profile: Option<&str>,
+            atomic: bool,
+            strict: bool,
+            verbose: bool)
+            -> Result<Deployment, error::DotfilerError> {
     let config = common::load_config(config_path)?;
     let templates_path = common::get_templates_path(config_path)?.to_string_lossy().to_string();
+    let (context, secrets) = common::get_context(&config, profile)?;
+    let renderer = renderer::Renderer::new(&templates_path, strict)?;
 
-    if let Some(ref dotfiles) = config.dotfiles {
-        for dotfile in dotfiles {
-            let template_path = common::resolve_path(&dotfile.template, Some(&templates_path))?;
-            let tar_path = [target_path, &common::resolve_path(&dotfile.target, None)?[1..]]
-                .concat();
+    let mut generation = generations::Generation::new(target_path)?;
+    let backup_path = generation.backup_path();
+
+    if atomic {
+        let dotfiles = get_active_dotfiles(&config, verbose);
+        let result = load_atomic(target_path,
+                                 &dotfiles,
+                                 &renderer,
+                                 &context,
+                                 &templates_path,
+                                 &mut generation);
+        generation.finish()?;
+        return result.map_err(|e| secrets.redact(&e.to_string()).into());
+    }
+
+    // Render every dotfile first, so undefined variables are reported before anything is written
+    let mut roots = Vec::new();
+    let mut undefined = Vec::new();
+    let mut failed = 0;
+    for dotfile in get_active_dotfiles(&config, verbose) {
+        match create_tree(dotfile, &renderer, &context, target_path, &templates_path) {
+            Ok(root) => roots.push((dotfile, root)),
+            Err(error::DotfilerError::UndefinedVariables(mut errors)) => {
+                undefined.append(&mut errors)
+            }
+            Err(e) => {
+                println!("{}", secrets.redact(&e.to_string()));
+                failed += 1;
+            }
+        }
+    }
 
-            // Create all required target directories before root
+    if !undefined.is_empty() {
+        generation.finish()?;
+        let msg = format!("Nothing has been saved because of undefined variables:\n{}",
+                          undefined.join("\n"));
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    let rendered = roots.len();
+    let roots = run_pre_deploy_hooks(roots, target_path);
+    failed += rendered - roots.len();
+
+    let mut changed = 0;
+    let mut changed_dotfiles = Vec::new();
+    for (dotfile, mut root) in roots {
+        // Create all required target directories before root
+        if let Ok(tar_path) = get_target_path(dotfile, target_path) {
             let _ = path::Path::new(&tar_path).parent().map(|p| fs::create_dir_all(&p));
+        }
 
-            let mut root = match filesystem::create_tree_from_path(&template_path, &tar_path) {
-                Ok(root) => root,
-                Err(e) => {
-                    println!("Can't create tree for template '{}':\n{}", template_path, e);
-                    continue;
-                }
-            };
-
-            if let Some(ref vars) = config.variables {
-                if let Err(e) = root.render(vars) {
-                    println!("Unable to template the template '{}':\n{}",
-                             template_path,
-                             e);
-                    continue;
+        match root.save(&backup_path) {
+            Ok(count) => {
+                changed += count;
+                generation.add_changes(root.changes());
+
+                if count > 0 {
+                    changed_dotfiles.push(ChangedDotfile::new(dotfile, target_path));
+                    run_post_deploy_hook(dotfile, target_path);
                 }
             }
-
-            if let Err(e) = root.save() {
-                println!("Unable to save the template '{}':\n{}", template_path, e);
+            Err(e) => {
+                println!("Unable to save the template '{}':\n{}",
+                         dotfile.template,
+                         secrets.redact(&e.to_string()));
+                failed += 1;
 
                 if let Err(e) = root.restore() {
                     println!("Critical Error! Unable to recover from failure.\n{}", e);
                 }
+            }
+        }
+    }
+
+    generation.finish()?;
+
+    print_summary(changed, failed);
+    Ok(Deployment {
+           changed: changed_dotfiles,
+           failed: failed,
+       })
+}
+
+fn print_summary(changed: usize, failed: usize) {
+    if failed == 0 {
+        println!("Every file has been templated, {} changed.", changed);
+    } else {
+        println!("{} files changed, {} templates could not be deployed.", changed, failed);
+    }
+}
+
+// Render every dotfile before writing anything and roll back all of them on failure
+fn load_atomic(target_path: &str,
+               dotfiles: &[&common::Dotfile],
+               renderer: &renderer::Renderer,
+               context: &value::Table,
+               templates_path: &str,
+               generation: &mut generations::Generation)
+               -> Result<Deployment, error::DotfilerError> {
+    let backup_path = generation.backup_path();
+    let mut roots = Vec::new();
+    let mut errors = Vec::new();
+
+    for &dotfile in dotfiles {
+        match create_tree(dotfile, renderer, context, target_path, templates_path) {
+            Ok(root) => roots.push((dotfile, root)),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
 
+    if !errors.is_empty() {
+        let msg = format!("Nothing has been saved because of invalid templates:\n{}",
+                          errors.join("\n"));
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    // A failing hook stops everything before anything is written, like an invalid template
+    for &(dotfile, ref root) in &roots {
+        if let Err(e) = run_pre_deploy_hook(dotfile, &**root, target_path) {
+            let msg = format!("Nothing has been saved because the pre_deploy hook of the \
+                               template '{}' failed:\n{}",
+                              dotfile.template,
+                              e);
+            return Err(error::DotfilerError::Message(msg));
+        }
+    }
+
+    let mut changed = 0;
+    let mut counts = Vec::new();
+    for i in 0..roots.len() {
+        // Create all required target directories before root
+        if let Ok(tar_path) = get_target_path(roots[i].0, target_path) {
+            let _ = path::Path::new(&tar_path).parent().map(|p| fs::create_dir_all(&p));
+        }
+
+        let error = match roots[i].1.save(&backup_path) {
+            Ok(count) => {
+                changed += count;
+                counts.push(count);
                 continue;
             }
+            Err(e) => e,
+        };
+
+        let mut msg = format!("Unable to save the template '{}':\n{}\nRolling back all changes.",
+                              roots[i].0.template,
+                              error);
+
+        // Restore in reverse order and report every failure, not just the first one
+        for &(dotfile, ref root) in roots[..i + 1].iter().rev() {
+            if let Err(e) = root.restore() {
+                msg.push_str(&format!("\nCritical Error! Unable to restore '{}':\n{}",
+                                      dotfile.target,
+                                      e));
+            }
+        }
+
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    // Only record the generation once everything has been saved successfully
+    for &(_, ref root) in &roots {
+        generation.add_changes(root.changes());
+    }
+
+    // Hooks run last, there is nothing left to roll back at this point
+    let mut changed_dotfiles = Vec::new();
+    for (&(dotfile, _), &count) in roots.iter().zip(&counts) {
+        if count > 0 {
+            changed_dotfiles.push(ChangedDotfile::new(dotfile, target_path));
+            run_post_deploy_hook(dotfile, target_path);
+        }
+    }
+
+    print_summary(changed, 0);
+    Ok(Deployment {
+           changed: changed_dotfiles,
+           failed: 0,
+       })
+}
+
+// Run the "pre_deploy" hook of every dotfile which is going to change
+// Dotfiles whose hook failed are skipped
+fn run_pre_deploy_hooks<'a>(roots: Vec<(&'a common::Dotfile, Box<filesystem::File>)>,
+                            target_path: &str)
+                            -> Vec<(&'a common::Dotfile, Box<filesystem::File>)> {
+    roots.into_iter()
+        .filter(|&(dotfile, ref root)| {
+            if let Err(e) = run_pre_deploy_hook(dotfile, &**root, target_path) {
+                println!("Skipping the template '{}', its pre_deploy hook failed:\n{}",
+                         dotfile.template,
+                         e);
+                return false;
+            }
+
+            true
+        })
+        .collect()
+}
+
+// Run the "pre_deploy" hook of a dotfile if it is going to change
+fn run_pre_deploy_hook(dotfile: &common::Dotfile,
+                       root: &filesystem::File,
+                       target_path: &str)
+                       -> Result<(), error::DotfilerError> {
+    let command = match dotfile.pre_deploy {
+        Some(ref command) => command,
+        None => return Ok(()),
+    };
+
+    // Unchanged dotfiles are not deployed, so their hooks don't run either
+    // A dotfile whose diff fails counts as changed, saving it reports the actual error
+    if root.diff().map(|diff| diff.is_empty()).unwrap_or(false) {
+        return Ok(());
+    }
+
+    run_hook(dotfile, command, target_path)
+}
+
+// Run the "post_deploy" hook of a dotfile which has been changed
+fn run_post_deploy_hook(dotfile: &common::Dotfile, target_path: &str) {
+    if let Some(ref command) = dotfile.post_deploy {
+        if let Err(e) = run_hook(dotfile, command, target_path) {
+            println!("The post_deploy hook of the template '{}' failed:\n{}",
+                     dotfile.template,
+                     e);
+        }
+    }
+}
+
+// Hooks act on the real system, so they never run while deploying to a dry run directory
+fn run_hook(dotfile: &common::Dotfile,
+            command: &str,
+            target_path: &str)
+            -> Result<(), error::DotfilerError> {
+    if target_path != "/" {
+        println!("Not running the hook '{}' of the template '{}' in a dry run.",
+                 command,
+                 dotfile.template);
+        return Ok(());
+    }
+
+    scripts::run_hook(command, &get_target_path(dotfile, target_path)?)
+}
+
+// Dotfiles whose conditions match the current machine
+pub fn get_active_dotfiles(config: &common::Config, verbose: bool) -> Vec<&common::Dotfile> {
+    let mut active = Vec::new();
+
+    if let Some(ref dotfiles) = config.dotfiles {
+        for dotfile in dotfiles {
+            // A condition which can't be checked only skips this dotfile
+            match common::get_skip_reason(dotfile) {
+                Ok(Some(reason)) => {
+                    if verbose {
+                        println!("Skipping the template '{}': {}", dotfile.template, reason);
+                    }
+                }
+                Ok(None) => active.push(dotfile),
+                Err(e) => {
+                    println!("Skipping the template '{}', its conditions can't be checked:\n{}",
+                             dotfile.template,
+                             e)
+                }
+            }
+        }
+    }
+
+    active
+}
+
+// Create the rendered tree of a dotfile without writing anything to the target
+pub fn create_tree(dotfile: &common::Dotfile,
+                   renderer: &renderer::Renderer,
+                   context: &value::Table,
+                   target_path: &str,
+                   templates_path: &str)
+                   -> Result<Box<filesystem::File>, error::DotfilerError> {
+    let template_path = common::resolve_path(&dotfile.template, Some(templates_path))?;
+    let tar_path = get_target_path(dotfile, target_path)?;
+
+    // Partials are only included by other templates
+    let partials_path = [templates_path, "/", helpers::PARTIALS_DIR].concat();
+    if path::Path::new(&template_path).starts_with(&partials_path) {
+        let msg = format!("The template '{}' is a partial and can't be deployed.",
+                          dotfile.template);
+        return Err(error::DotfilerError::Message(msg));
+    }
+
+    // Linked templates are not rendered, changes to the target land in the template directly
+    if common::is_linked(dotfile)? {
+        return filesystem::create_link(&template_path, &tar_path);
+    }
+
+    let mut root = match filesystem::create_tree_from_path(&template_path, &tar_path) {
+        Ok(root) => root,
+        Err(e) => {
+            let msg = format!("Can't create tree for template '{}':\n{}", template_path, e);
+            return Err(error::DotfilerError::Message(msg));
+        }
+    };
+
+    match root.render(renderer, context) {
+        Ok(()) => (),
+        Err(e @ error::DotfilerError::UndefinedVariables(_)) => return Err(e),
+        Err(e) => {
+            let msg = format!("Unable to template the template '{}':\n{}", template_path, e);
+            return Err(error::DotfilerError::Message(msg));
+        }
+    }
+
+    root.set_permissions(&get_permission_rules(dotfile, &tar_path)?);
+
+    Ok(root)
+}
+
+// Permissions and ownership declared for a dotfile, applied over the template's permissions
+fn get_permission_rules(dotfile: &common::Dotfile,
+                        tar_path: &str)
+                        -> Result<filesystem::PermissionRules, error::DotfilerError> {
+    let mode = common::get_mode(dotfile)?;
+
+    let mut patterns = Vec::new();
+    if let Some(ref permission_patterns) = dotfile.permission_patterns {
+        for (pattern, mode) in permission_patterns {
+            patterns.push((pattern.clone(), common::parse_mode(mode)?));
         }
     }
 
-    println!("Every file as been templated.");
-    Ok(())
+    Ok(filesystem::PermissionRules {
+           root_path: tar_path.to_string(),
+           mode: mode,
+           patterns: patterns,
+           uid: match dotfile.owner {
+               Some(ref owner) => Some(common::get_uid(owner)?),
+               None => None,
+           },
+           gid: match dotfile.group {
+               Some(ref group) => Some(common::get_gid(group)?),
+               None => None,
+           },
+       })
+}
+
+// Location of the dotfile's target below the root directory "target_path"
+pub fn get_target_path(dotfile: &common::Dotfile,
+                       target_path: &str)
+                       -> Result<String, error::DotfilerError> {
+    Ok([target_path, &common::resolve_path(&dotfile.target, None)?[1..]].concat())
+}
+
+// Every variable used by a template, partials are included because the template might use them
+pub fn get_references(dotfile: &common::Dotfile,
+                      templates_path: &str)
+                      -> Result<Vec<String>, error::DotfilerError> {
+    let template_path = common::resolve_path(&dotfile.template, Some(templates_path))?;
+    let tar_path = get_target_path(dotfile, "/")?;
+    let mut sources = filesystem::create_tree_from_path(&template_path, &tar_path)?.sources()?;
+    sources.append(&mut helpers::read_partials(templates_path)?);
+
+    let handlebars = helpers::create_handlebars();
+    Ok(sources.iter()
+           .flat_map(|&(_, ref template)| renderer::get_references(&handlebars, template))
+           .map(|reference| reference.name)
+           .collect())
 }
 
 
@@ -59,7 +420,11 @@ pub fn load(target_path: &str, config_path: &str) -> Result<(), error::DotfilerE
 #[test]
 fn load_correctly_saving_example_to_dummy_dir() {
     load("./example/",
-         "/home/undeadleech/Programming/Rust/dotfiler/examples/config.toml")
+         "/home/undeadleech/Programming/Rust/dotfiler/examples/config.toml",
+         None,
+         false,
+         true,
+         false)
             .unwrap();
 
     let file1_ok = fs::metadata("./example/home/undeadleech/testing/Xresources").is_ok();
@@ -78,7 +443,11 @@ fn load_correctly_saving_example_to_dummy_dir() {
 #[test]
 fn load_copying_symlinks_not_target() {
     load("./symlink/",
-         "/home/undeadleech/Programming/Rust/dotfiler/examples/config.toml")
+         "/home/undeadleech/Programming/Rust/dotfiler/examples/config.toml",
+         None,
+         false,
+         true,
+         false)
             .unwrap();
 
     let is_symlink = fs::symlink_metadata("./symlink/home/undeadleech/testing/config")
@@ -90,3 +459,93 @@ fn load_copying_symlinks_not_target() {
 
     assert_eq!(is_symlink, true);
 }
+
+#[test]
+fn load_skipping_unchanged_files() {
+    load("./unchanged/",
+         "/home/undeadleech/Programming/Rust/dotfiler/examples/config.toml",
+         None,
+         false,
+         true,
+         false)
+            .unwrap();
+    let changed = load("./unchanged/",
+                       "/home/undeadleech/Programming/Rust/dotfiler/examples/config.toml",
+                       None,
+                       false,
+                       true,
+                       false)
+            .unwrap();
+
+    let _ = fs::remove_dir_all("./unchanged/");
+
+    assert!(changed.changed.is_empty());
+}
+
+#[test]
+fn skip_dotfiles_with_invalid_conditions() {
+    let config: common::Config =
+        ::toml::from_str("[[dotfiles]]\ntemplate = \"a\"\ntarget = \"/tmp/a\"\n\
+                          if_exists = \"$DOTFILER_UNDEFINED_TEST_VARIABLE/x\"\n\
+                          [[dotfiles]]\ntemplate = \"b\"\ntarget = \"/tmp/b\"")
+                .unwrap();
+    let active = get_active_dotfiles(&config, false);
+
+    assert!(common::get_skip_reason(&config.dotfiles.as_ref().unwrap()[0]).is_err());
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0].template, "b");
+}
+
+#[test]
+fn hooks_run_for_failing_diffs_but_not_in_dry_runs() {
+    let _ = fs::create_dir_all("./hooks");
+    fs::File::create("./hooks/a").unwrap();
+    fs::File::create("./hooks/broken.age").unwrap();
+    let config: common::Config =
+        ::toml::from_str("[[dotfiles]]\ntemplate = \"a\"\ntarget = \"/tmp/a\"\n\
+                          pre_deploy = \"touch ./hooks/ran\"")
+                .unwrap();
+    let dotfile = &config.dotfiles.as_ref().unwrap()[0];
+    let broken = || filesystem::create_template_tree("./hooks/a", "./hooks/broken.age");
+
+    let dry = run_pre_deploy_hooks(vec![(dotfile, broken().unwrap())], "./hooks/");
+    let ran_dry = fs::metadata("./hooks/ran").is_ok();
+    let deployed = run_pre_deploy_hooks(vec![(dotfile, broken().unwrap())], "/");
+    let ran = fs::metadata("./hooks/ran").is_ok();
+
+    let _ = fs::remove_dir_all("./hooks/");
+
+    assert_eq!((dry.len(), ran_dry), (1, false));
+    assert_eq!((deployed.len(), ran), (1, true));
+}
+
+#[test]
+fn load_atomic_rolling_back_every_dotfile() {
+    use std::io::Write;
+    let _ = fs::create_dir_all("./atomic/templates");
+    let write = |path: &str, content: &str| {
+        fs::File::create(path).and_then(|mut f| f.write_all(content.as_bytes())).unwrap()
+    };
+    write("./atomic/config.toml",
+          "[variables]\nx = \"1\"\n[[dotfiles]]\ntemplate = \"a\"\ntarget = \"/atomic_a\"\n\
+           [[dotfiles]]\ntemplate = \"b\"\ntarget = \"/atomic_b\"");
+    write("./atomic/templates/a", "{{ x }}");
+    write("./atomic/templates/b", "{{ missing }}");
+
+    // Undefined variables in strict mode stop everything before anything is written
+    let undefined = load("./atomic/root/", "./atomic/config.toml", None, true, true, false);
+    let undefined_saved = fs::metadata("./atomic/root/atomic_a").is_ok();
+
+    // The second target can't be written, so the first one has to be rolled back
+    write("./atomic/templates/b", "b");
+    let _ = fs::create_dir_all("./atomic/root/atomic_b/dir");
+    let failed = load("./atomic/root/", "./atomic/config.toml", None, true, true, false);
+    let failed_saved = fs::metadata("./atomic/root/atomic_a").is_ok();
+
+    let _ = fs::remove_dir_all("./atomic/");
+
+    assert!(undefined.is_err());
+    assert!(!undefined_saved);
+    assert!(failed.is_err());
+    assert!(!failed_saved);
+}